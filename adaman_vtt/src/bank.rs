@@ -28,6 +28,15 @@ pub fn get_new_id() -> DataId {
     DataId(Uuid::new_v4())
 }
 
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+//Used for the LoadIdentifier hash and to check downloaded data against it
+//FNV-1a, so every peer gets the same hash whatever machine it's on
+pub fn hash_data(data: &[u8]) -> u64 {
+    data.iter().fold(FNV_OFFSET, |hash, byte| (hash ^ *byte as u64).wrapping_mul(FNV_PRIME))
+}
+
 #[cfg(not(target_family = "wasm"))]
 #[derive(Resource)]
 pub struct Bank {
@@ -66,9 +75,19 @@ impl Bank {
         self.store_at_id(&id, data)
     }
     
+    //A fresh identifier for data we already have, with the hash worked out again from its bytes
+    pub fn identify(&self, id: &DataId) -> Option<fileload::LoadIdentifier> {
+        let data = self.request_data(id)?;
+        Some(fileload::LoadIdentifier{
+            data_id: *id,
+            size: data.len(),
+            hash: hash_data(&data),
+        })
+    }
+
     pub fn store_at_id(&mut self, id: &DataId, data: Arc<Vec<u8>>) -> fileload::LoadIdentifier {
         let size = (*data).len();
-        let hash = (*data).reflect_hash().expect("Unable to hash vec<u8>");
+        let hash = hash_data(&data);
 
         self.insert_data(id, data);
 
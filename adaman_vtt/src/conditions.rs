@@ -0,0 +1,319 @@
+use bevy::prelude::*;
+use bevy_mod_picking::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::initiative;
+use crate::networking;
+use crate::orders;
+use crate::roles;
+use crate::tokens;
+
+pub struct ConditionsPlugin;
+
+impl Plugin for ConditionsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, update_condition_icons)
+            .add_systems(Update, tick_conditions)
+        ;
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum Condition {
+    Blinded,
+    Charmed,
+    Deafened,
+    Exhaustion,
+    Frightened,
+    Grappled,
+    Incapacitated,
+    Invisible,
+    Paralyzed,
+    Petrified,
+    Poisoned,
+    Prone,
+    Restrained,
+    Stunned,
+    Unconscious,
+    Concentrating,
+    //Anything else the table wants to keep track of
+    Custom(String),
+}
+
+pub const CONDITIONS: [Condition; 16] = [
+    Condition::Blinded,
+    Condition::Charmed,
+    Condition::Deafened,
+    Condition::Exhaustion,
+    Condition::Frightened,
+    Condition::Grappled,
+    Condition::Incapacitated,
+    Condition::Invisible,
+    Condition::Paralyzed,
+    Condition::Petrified,
+    Condition::Poisoned,
+    Condition::Prone,
+    Condition::Restrained,
+    Condition::Stunned,
+    Condition::Unconscious,
+    Condition::Concentrating,
+];
+
+impl Condition {
+    pub fn name(&self) -> &str {
+        match self {
+            Condition::Blinded => "Blinded",
+            Condition::Charmed => "Charmed",
+            Condition::Deafened => "Deafened",
+            Condition::Exhaustion => "Exhaustion",
+            Condition::Frightened => "Frightened",
+            Condition::Grappled => "Grappled",
+            Condition::Incapacitated => "Incapacitated",
+            Condition::Invisible => "Invisible",
+            Condition::Paralyzed => "Paralyzed",
+            Condition::Petrified => "Petrified",
+            Condition::Poisoned => "Poisoned",
+            Condition::Prone => "Prone",
+            Condition::Restrained => "Restrained",
+            Condition::Stunned => "Stunned",
+            Condition::Unconscious => "Unconscious",
+            Condition::Concentrating => "Concentrating",
+            Condition::Custom(name) => name,
+        }
+    }
+
+    //The color of the condition's icon on the board
+    pub fn color(&self) -> [f32; 3] {
+        match self {
+            Condition::Blinded => [0.2, 0.2, 0.2],
+            Condition::Charmed => [1., 0.4, 0.7],
+            Condition::Deafened => [0.6, 0.6, 0.6],
+            Condition::Exhaustion => [0.55, 0.35, 0.2],
+            Condition::Frightened => [0.6, 0., 0.8],
+            Condition::Grappled => [0.9, 0.6, 0.1],
+            Condition::Incapacitated => [0.5, 0.5, 0.],
+            Condition::Invisible => [0.85, 0.95, 1.],
+            Condition::Paralyzed => [1., 1., 0.],
+            Condition::Petrified => [0.45, 0.45, 0.4],
+            Condition::Poisoned => [0.2, 0.8, 0.1],
+            Condition::Prone => [0.7, 0.4, 0.],
+            Condition::Restrained => [0.4, 0.25, 0.1],
+            Condition::Stunned => [1., 0.85, 0.3],
+            Condition::Unconscious => [0.1, 0.1, 0.5],
+            Condition::Concentrating => [0.2, 0.6, 1.],
+            Condition::Custom(_) => [1., 1., 1.],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct StatusMarker {
+    pub condition: Condition,
+    //Rounds until it wears off, None lasts until it's taken off
+    pub rounds: Option<u32>,
+}
+
+//Everything currently affecting a token on the board
+#[derive(Component, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct TokenConditions(pub Vec<StatusMarker>);
+
+pub fn set_conditions(
+    id: tokens::TokenId,
+    conditions: TokenConditions,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    ev_client.send(networking::ClientCommandEvent {
+        order: orders::OrderEvent {
+            command: orders::Command::SetConditions(orders::SetConditionsCommand {
+                id,
+                conditions,
+            }),
+        },
+        reliability: networking::NetworkReliability::Reliable,
+    });
+}
+
+//One of the small markers around a token's ring
+#[derive(Component)]
+pub struct ConditionIcon;
+
+const ICON_RADIUS: f32 = 0.6;
+//Space between icons around the ring, in radians
+const ICON_SPACING: f32 = 0.45;
+
+#[allow(clippy::type_complexity)]
+fn update_condition_icons(
+    mut commands: Commands,
+    tokens: Query<(Entity, &TokenConditions, &tokens::StrippedTokenData, Option<&Children>), Or<(Changed<TokenConditions>, Changed<tokens::StrippedTokenData>)>>,
+    icons: Query<(), With<ConditionIcon>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, conditions, data, children) in tokens.iter() {
+        for child in children.into_iter().flatten() {
+            if icons.contains(*child) {
+                commands.entity(*child).despawn_recursive();
+            }
+        }
+        let radius = data.get_radius();
+        let mesh = meshes.add(shape::Circle::new(ICON_RADIUS).into());
+        for (index, marker) in conditions.0.iter().enumerate() {
+            //Clockwise around the ring from the top
+            let angle = std::f32::consts::FRAC_PI_2 - ICON_SPACING * (index as f32 + 1.);
+            let [r, g, b] = marker.condition.color();
+            let icon = commands.spawn((
+                PbrBundle {
+                    mesh: mesh.clone(),
+                    material: materials.add(StandardMaterial {
+                        base_color: Color::rgb(r, g, b),
+                        unlit: true,
+                        ..default()
+                    }),
+                    //Local +z is towards the camera
+                    transform: Transform::from_xyz(radius * angle.cos(), radius * angle.sin(), 0.15),
+                    ..default()
+                },
+                Pickable::IGNORE,
+                ConditionIcon,
+            )).id();
+            commands.entity(entity).push_children(&[icon]);
+        }
+    }
+}
+
+//Durations count down at the start of the token's turn, only the GM does it so everyone gets the same result
+fn tick_conditions(
+    roles: Res<roles::Roles>,
+    initiative: Res<initiative::Initiative>,
+    tokens: Query<(&tokens::TokenId, &TokenConditions)>,
+    mut last_turn: Local<Option<(u32, usize, tokens::TokenId)>>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+) {
+    if !initiative.is_changed() || !roles.is_game_master() {
+        return;
+    }
+    let Some(active) = initiative.active() else {
+        *last_turn = None;
+        return;
+    };
+    if let Some((round, turn, id)) = *last_turn {
+        //Still the same turn, someone was just rolled in or taken out around it
+        if id == active && round == initiative.round {
+            *last_turn = Some((initiative.round, initiative.turn, active));
+            return;
+        }
+        //Going back a turn, or forward again to one that's already ticked
+        if (initiative.round, initiative.turn) <= (round, turn) {
+            return;
+        }
+    }
+    *last_turn = Some((initiative.round, initiative.turn, active));
+    let Some((_, conditions)) = tokens.iter().find(|(id, _)| **id == active) else {
+        return;
+    };
+    let mut new_conditions = conditions.clone();
+    new_conditions.0.retain_mut(|marker| match marker.rounds.as_mut() {
+        Some(rounds) => {
+            *rounds = rounds.saturating_sub(1);
+            *rounds > 0
+        },
+        None => true,
+    });
+    if new_conditions != *conditions {
+        set_conditions(active, new_conditions, &mut ev_client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::ecs::event::Events;
+
+    fn token(n: u128) -> tokens::TokenId {
+        tokens::TokenId(uuid::Uuid::from_u128(n))
+    }
+
+    fn marker(condition: Condition, rounds: Option<u32>) -> StatusMarker {
+        StatusMarker {
+            condition,
+            rounds,
+        }
+    }
+
+    //Two tokens in initiative, the first with a few markers on it
+    fn setup(role: roles::Role) -> (World, Schedule) {
+        let mut world = World::new();
+        let mut roles = roles::Roles::new();
+        roles.local = role;
+        world.insert_resource(roles);
+        let mut initiative = initiative::Initiative::default();
+        for (n, roll) in [(1, 15), (2, 10)] {
+            initiative.add(initiative::InitiativeEntry {
+                id: token(n),
+                roll,
+                dexterity: 10,
+            });
+        }
+        world.insert_resource(initiative);
+        world.init_resource::<Events<networking::ClientCommandEvent>>();
+        world.spawn((token(1), TokenConditions(vec![
+            marker(Condition::Poisoned, Some(2)),
+            marker(Condition::Stunned, Some(1)),
+            marker(Condition::Prone, None),
+        ])));
+        world.spawn((token(2), TokenConditions(vec![marker(Condition::Blinded, Some(3))])));
+        let mut schedule = Schedule::default();
+        schedule.add_systems(tick_conditions);
+        (world, schedule)
+    }
+
+    fn turn(world: &mut World, schedule: &mut Schedule, change: impl FnOnce(&mut initiative::Initiative)) -> Vec<(tokens::TokenId, TokenConditions)> {
+        change(&mut world.resource_mut::<initiative::Initiative>());
+        schedule.run(world);
+        world.resource_mut::<Events<networking::ClientCommandEvent>>()
+            .drain()
+            .filter_map(|ev| match ev.order.command {
+                orders::Command::SetConditions(cmd) => Some((cmd.id, cmd.conditions)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn counts_down_at_the_start_of_a_turn() {
+        let (mut world, mut schedule) = setup(roles::Role::GameMaster);
+        assert!(turn(&mut world, &mut schedule, |_| {}).is_empty());
+        let sent = turn(&mut world, &mut schedule, |initiative| initiative.next_turn());
+        assert!(sent == vec![(token(1), TokenConditions(vec![
+            marker(Condition::Poisoned, Some(1)),
+            marker(Condition::Prone, None),
+        ]))]);
+    }
+
+    #[test]
+    fn going_back_doesnt_tick_again() {
+        let (mut world, mut schedule) = setup(roles::Role::GameMaster);
+        turn(&mut world, &mut schedule, |initiative| initiative.next_turn());
+        assert_eq!(turn(&mut world, &mut schedule, |initiative| initiative.next_turn()).len(), 1);
+        assert!(turn(&mut world, &mut schedule, |initiative| initiative.previous_turn()).is_empty());
+        assert!(turn(&mut world, &mut schedule, |initiative| initiative.next_turn()).is_empty());
+    }
+
+    #[test]
+    fn reordering_doesnt_tick() {
+        let (mut world, mut schedule) = setup(roles::Role::GameMaster);
+        turn(&mut world, &mut schedule, |initiative| initiative.next_turn());
+        let sent = turn(&mut world, &mut schedule, |initiative| initiative.add(initiative::InitiativeEntry {
+            id: token(3),
+            roll: 20,
+            dexterity: 10,
+        }));
+        assert!(sent.is_empty());
+    }
+
+    #[test]
+    fn only_the_gm_ticks() {
+        let (mut world, mut schedule) = setup(roles::Role::Player);
+        assert!(turn(&mut world, &mut schedule, |initiative| initiative.next_turn()).is_empty());
+    }
+}
@@ -8,14 +8,71 @@ impl From<DD2VTT> for maps::MapData {
             x.format,
             x.image,
             maps::MapGrid{
-                pixels_per: x.resolution.pixels_per_grid,
+                pixels_per: x.resolution.pixels_per_grid as f64,
                 width: x.resolution.map_size.x,
                 height: x.resolution.map_size.y,
-            }
+                offset_x: 0.,
+                offset_y: 0.,
+                origin_x: x.resolution.map_origin.x as f64,
+                origin_y: x.resolution.map_origin.y as f64,
+            },
+            x.line_of_sight.iter()
+                .map(|line| maps::Wall{
+                    points: line.iter()
+                        .map(|point| maps::GridPoint{
+                            x: point.x,
+                            y: point.y,
+                        })
+                        .collect(),
+                })
+                .collect(),
+            x.portals.iter()
+                .map(|portal| maps::Door{
+                    position: maps::GridPoint{
+                        x: portal.position.x,
+                        y: portal.position.y,
+                    },
+                    bounds: portal.bounds.iter()
+                        .map(|bound| maps::GridPoint{
+                            x: bound.x,
+                            y: bound.y,
+                        })
+                        .collect(),
+                    closed: portal.closed,
+                })
+                .collect(),
+            x.lights.iter()
+                .map(|light| maps::MapLight{
+                    position: maps::GridPoint{
+                        x: light.position.x,
+                        y: light.position.y,
+                    },
+                    range: light.range,
+                    intensity: light.intensity,
+                    color: parse_color(&light.color),
+                    shadows: light.shadows,
+                })
+                .collect(),
+            maps::MapEnvironment{
+                baked_lighting: x.environment.baked_lighting,
+                ambient_light: parse_color(&x.environment.ambient_light),
+            },
         )
     }
 }
 
+//Colors are stored as AARRGGBB hex, fall back to white if it's anything else
+fn parse_color(hex: &str) -> [f32; 4] {
+    let Ok(value) = u32::from_str_radix(hex.trim_start_matches('#'), 16) else {
+        return [1., 1., 1., 1.];
+    };
+    let channel = |shift: u32| ((value >> shift) & 0xff) as f32 / 255.;
+    if hex.trim_start_matches('#').len() <= 6 {
+        return [channel(16), channel(8), channel(0), 1.];
+    }
+    [channel(16), channel(8), channel(0), channel(24)]
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DD2VTT {
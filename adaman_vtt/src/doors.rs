@@ -0,0 +1,133 @@
+use bevy::prelude::*;
+use bevy_mod_picking::prelude::*;
+
+use crate::fog;
+use crate::input;
+use crate::maps;
+use crate::roles;
+use crate::vision;
+
+pub struct DoorPlugin;
+
+impl Plugin for DoorPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, spawn_doors)
+            .add_systems(Update, update_doors.after(spawn_doors))
+            .add_systems(Update, hide_unseen_doors.after(spawn_doors).after(vision::update_vision_masks))
+        ;
+    }
+}
+
+//One of a map's doors, a child of the map so it can look up whether it's closed
+#[derive(Component)]
+pub struct DoorIndex(pub usize);
+
+//How close a token has to be to open or close a door, a step past standing next to it
+const DOOR_REACH: f32 = maps::CELL_SIZE * 2.;
+
+pub fn in_reach(dimensions: &maps::MapDimensions, transform: &GlobalTransform, index: usize, position: Vec2) -> bool {
+    let Some(door) = dimensions.doors.get(index) else {
+        return false;
+    };
+    dimensions.line_segments(transform, &door.bounds)
+        .iter()
+        .any(|(start, end)| distance_to_segment(position, *start, *end) <= DOOR_REACH)
+}
+
+fn distance_to_segment(point: Vec2, start: Vec2, end: Vec2) -> f32 {
+    let along = end - start;
+    let fraction = if along.length_squared() > 0. {
+        ((point - start).dot(along) / along.length_squared()).clamp(0., 1.)
+    } else {
+        0.
+    };
+    point.distance(start + along * fraction)
+}
+
+//How far the doors stick up off the map
+const DOOR_THICKNESS: f32 = 0.5;
+const DOOR_HEIGHT: f32 = 0.2;
+
+fn spawn_doors(
+    mut commands: Commands,
+    maps: Query<(Entity, &maps::MapDimensions, &maps::MapDoors), Added<maps::MapDimensions>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, dimensions, states) in maps.iter() {
+        for (index, door) in dimensions.doors.iter().enumerate() {
+            let (Some(start), Some(end)) = (door.bounds.first(), door.bounds.last()) else {
+                continue;
+            };
+            let start = dimensions.grid_to_local(start.x, start.y);
+            let end = dimensions.grid_to_local(end.x, end.y);
+            let along = end - start;
+            let door_box = shape::Box::new(along.length(), DOOR_THICKNESS, DOOR_HEIGHT);
+            let transform = Transform::from_translation((start + end) / 2. + Vec3::Z * DOOR_HEIGHT / 2.)
+                .with_rotation(Quat::from_rotation_z(along.y.atan2(along.x)));
+            let door = commands.spawn((
+                PbrBundle {
+                    mesh: meshes.add(door_box.into()),
+                    material: materials.add(StandardMaterial {
+                        base_color: door_color(states.0.get(index).copied().unwrap_or(door.closed)),
+                        alpha_mode: AlphaMode::Blend,
+                        ..default()
+                    }),
+                    transform,
+                    ..default()
+                },
+                PickableBundle::default(),
+                On::<Pointer<Click>>::send_event::<input::DoorClickEvent>(),
+                DoorIndex(index),
+            )).id();
+            commands.entity(entity).push_children(&[door]);
+        }
+    }
+}
+
+fn door_color(closed: bool) -> Color {
+    if closed {
+        Color::rgb(0.45, 0.3, 0.15)
+    } else {
+        Color::rgba(0.45, 0.3, 0.15, 0.3)
+    }
+}
+
+fn update_doors(
+    maps: Query<&maps::MapDoors, Changed<maps::MapDoors>>,
+    doors: Query<(&DoorIndex, &Parent, &Handle<StandardMaterial>)>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (index, parent, material) in doors.iter() {
+        let Ok(states) = maps.get(parent.get()) else {
+            continue;
+        };
+        let Some(closed) = states.0.get(index.0) else {
+            continue;
+        };
+        if let Some(mat) = materials.get_mut(material) {
+            mat.base_color = door_color(*closed);
+        }
+    }
+}
+
+//Doors stick up through the darkness, so players only get to see the ones they've come across
+fn hide_unseen_doors(
+    roles: Res<roles::Roles>,
+    los: Res<vision::LineOfSight>,
+    maps: Query<(&maps::MapDimensions, &GlobalTransform, &fog::MapFog, &vision::Explored)>,
+    mut doors: Query<(&GlobalTransform, &Parent, &mut Visibility), With<DoorIndex>>,
+) {
+    for (door_transform, parent, mut visibility) in doors.iter_mut() {
+        let Ok((dimensions, transform, fog, explored)) = maps.get(parent.get()) else {
+            continue;
+        };
+        let center = door_transform.translation();
+        let seen = roles.is_game_master() || dimensions.world_to_texel(transform, Vec2::new(center.x, center.z))
+            .map_or(true, |(x, y)| !fog.is_hidden(x, y) && (!los.active || explored.near(x, y)));
+        let wanted = if seen { Visibility::Inherited } else { Visibility::Hidden };
+        if *visibility != wanted {
+            *visibility = wanted;
+        }
+    }
+}
@@ -5,9 +5,16 @@ use std::sync::Arc;
 use crate::orders;
 use crate::bank;
 use crate::fileload;
-use crate::maps::MapId;
-use crate::tokens::TokenId;
+use crate::maps::{MapId, MapDoors};
+use crate::tokens::{TokenId, TokenOwner, TokenLock};
 use crate::files;
+use crate::networking;
+use crate::roles;
+use crate::fog::MapFog;
+use crate::health::TokenHealth;
+use crate::initiative::Initiative;
+use crate::conditions::TokenConditions;
+use crate::lighting::Lighting;
 
 
 pub struct EncounterPlugin;
@@ -19,12 +26,16 @@ impl Plugin for EncounterPlugin {
             .add_event::<EncounterLoad>()
             .add_systems(Update, save_encounter)
             .add_event::<EncounterSave>()
+            .add_systems(Update, sync_encounter)
+            .add_event::<EncounterSync>()
+            .add_systems(Update, send_snapshot)
+            .init_resource::<SnapshotBuffer>()
         ;
     }
 }
 
 #[derive(Resource)]
-pub struct CurrentEncounterID(bank::DataId);
+pub struct CurrentEncounterID(pub bank::DataId);
 
 fn setup_default_encounter(
     mut commands: Commands,
@@ -40,25 +51,19 @@ pub struct EncounterLoad {
     pub data: Arc<Vec<u8>>,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn load_encounter(
     mut commands: Commands,
     mut ev_encounter_load: EventReader<EncounterLoad>,
-    maps: Query<(Entity, With<MapId>)>,
-    tokens: Query<(Entity, With<TokenId>)>,
+    maps: Query<Entity, With<MapId>>,
+    tokens: Query<Entity, With<TokenId>>,
     mut current_encounter: ResMut<CurrentEncounterID>,
+    mut initiative: ResMut<Initiative>,
+    mut lighting: ResMut<Lighting>,
     mut map_creation: EventWriter<orders::CreateMapCommand>,
     mut token_creation: EventWriter<orders::CreateTokenCommand>,
 ) {
     for ev in ev_encounter_load.read() {
-        //Remove all the old maps
-        for (map, _) in maps.iter() {
-            commands.entity(map).despawn_recursive();
-        }
-        //Remove all the old tokens
-        for (token, _) in tokens.iter() {
-            commands.entity(token).despawn_recursive();
-        }
-        //
         //Update the current encounter resource
         current_encounter.0 = ev.data_id;
 
@@ -67,13 +72,146 @@ fn load_encounter(
             continue;
         };
 
-        //Actually handle the loading of entities
-        for map_load in data.map_instances.iter() {
-            map_creation.send(map_load.command.clone())
+        replace_encounter(&mut commands, &maps, &tokens, &data, &mut initiative, &mut lighting, &mut map_creation, &mut token_creation);
+    }
+}
+
+//Sync Encounter, the current state of the board sent straight from the GM
+#[derive(Event)]
+pub struct EncounterSync {
+    pub data_id: bank::DataId,
+    pub encounter: Encounter,
+}
+
+//Snapshot parts recieved so far, put back together once the last one arrives
+#[derive(Resource, Default)]
+pub struct SnapshotBuffer {
+    data_id: Option<bank::DataId>,
+    next_part: u32,
+    bytes: Vec<u8>,
+}
+
+impl SnapshotBuffer {
+    pub fn add(&mut self, part: &orders::SyncEncounterCommand) -> Option<Encounter> {
+        //A new snapshot throws away whatever was left of an old one
+        if part.part == 0 {
+            self.data_id = Some(part.data_id);
+            self.next_part = 0;
+            self.bytes.clear();
+        }
+        if self.data_id != Some(part.data_id) || part.part != self.next_part {
+            println!("Snapshot part out of order");
+            return None;
+        }
+        self.bytes.extend_from_slice(&part.bytes);
+        self.next_part += 1;
+        if self.next_part < part.parts {
+            return None;
+        }
+        self.data_id = None;
+        let bytes = std::mem::take(&mut self.bytes);
+        let encounter = serde_json::from_slice::<Encounter>(&bytes).ok();
+        if encounter.is_none() {
+            println!("Bad Snapshot Data");
+        }
+        encounter
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sync_encounter(
+    mut commands: Commands,
+    mut ev_encounter_sync: EventReader<EncounterSync>,
+    maps: Query<Entity, With<MapId>>,
+    tokens: Query<Entity, With<TokenId>>,
+    mut current_encounter: ResMut<CurrentEncounterID>,
+    mut initiative: ResMut<Initiative>,
+    mut lighting: ResMut<Lighting>,
+    mut map_creation: EventWriter<orders::CreateMapCommand>,
+    mut token_creation: EventWriter<orders::CreateTokenCommand>,
+) {
+    for ev in ev_encounter_sync.read() {
+        current_encounter.0 = ev.data_id;
+        replace_encounter(&mut commands, &maps, &tokens, &ev.encounter, &mut initiative, &mut lighting, &mut map_creation, &mut token_creation);
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn replace_encounter(
+    commands: &mut Commands,
+    maps: &Query<Entity, With<MapId>>,
+    tokens: &Query<Entity, With<TokenId>>,
+    data: &Encounter,
+    initiative: &mut Initiative,
+    lighting: &mut Lighting,
+    map_creation: &mut EventWriter<orders::CreateMapCommand>,
+    token_creation: &mut EventWriter<orders::CreateTokenCommand>,
+) {
+    //Remove all the old maps
+    for map in maps.iter() {
+        commands.entity(map).despawn_recursive();
+    }
+    //Remove all the old tokens
+    for token in tokens.iter() {
+        commands.entity(token).despawn_recursive();
+    }
+
+    //Actually handle the loading of entities
+    for map_load in data.map_instances.iter() {
+        map_creation.send(map_load.command.clone())
+    }
+    //Actually handle the loading of entities
+    for token_load in data.token_instances.iter() {
+        token_creation.send(token_load.command.clone())
+    }
+    *initiative = data.initiative.clone();
+    //Older encounters never had it picked, so their maps decide
+    lighting.chosen = data.dynamic_lighting.is_some();
+    if let Some(dynamic) = data.dynamic_lighting {
+        lighting.dynamic = dynamic;
+    }
+}
+
+//Same size as a file section, big boards would go over the packet limit in one piece
+const SNAPSHOT_PART_BYTES: usize = 16 * 1024;
+
+//Once a new peer has said who they are, the GM sends them everything on the board
+#[allow(clippy::too_many_arguments)]
+fn send_snapshot(
+    roles: Res<roles::Roles>,
+    mut ev_announced: EventReader<roles::PeerAnnouncedRole>,
+    maps: Query<(&fileload::LoadIdentifier, &MapId, &Transform, &MapDoors, &MapFog)>,
+    tokens: TokenInstances,
+    initiative: Res<Initiative>,
+    lighting: Res<Lighting>,
+    current_encounter: Res<CurrentEncounterID>,
+    mut ev_networked: EventWriter<networking::NetworkedCommandEvent>,
+) {
+    if !roles.is_game_master() {
+        ev_announced.clear();
+        return;
+    }
+    let mut parts = Vec::<Vec<u8>>::new();
+    for ev in ev_announced.read() {
+        //Built once for everyone who joined this frame
+        if parts.is_empty() {
+            let enc = build_encounter(&maps, &tokens, &initiative, &lighting);
+            let enc_data = serde_json::to_vec(&enc).expect("Unable to serialize encounter data");
+            parts = enc_data.chunks(SNAPSHOT_PART_BYTES).map(|part| part.to_vec()).collect();
         }
-        //Actually handle the loading of entities
-        for token_load in data.token_instances.iter() {
-            token_creation.send(token_load.command.clone())
+        for (index, bytes) in parts.iter().enumerate() {
+            ev_networked.send(networking::NetworkedCommandEvent{
+                order: orders::OrderEvent{
+                    command: orders::Command::SyncEncounter(orders::SyncEncounterCommand{
+                        data_id: current_encounter.0,
+                        part: index as u32,
+                        parts: parts.len() as u32,
+                        bytes: bytes.clone(),
+                    }),
+                },
+                reliability: networking::NetworkReliability::Reliable,
+                peer_id: networking::RecepientPeer::Peer(ev.peer_id),
+            });
         }
     }
 }
@@ -84,45 +222,19 @@ pub struct EncounterSave{
     pub name: String,
 }
 
+#[allow(clippy::too_many_arguments)]
 fn save_encounter(
     mut ev_encounter_save: EventReader<EncounterSave>,
-    maps: Query<(&fileload::LoadIdentifier, &MapId, &Transform)>,
-    tokens: Query<(&fileload::LoadIdentifier, &TokenId, &Transform)>,
+    maps: Query<(&fileload::LoadIdentifier, &MapId, &Transform, &MapDoors, &MapFog)>,
+    tokens: TokenInstances,
+    initiative: Res<Initiative>,
+    lighting: Res<Lighting>,
     current_encounter: ResMut<CurrentEncounterID>,
     mut bank: ResMut<bank::Bank>,
     mut ev_register_encounter: EventWriter<files::RegisterEncounter>,
 ) {
     for ev in ev_encounter_save.read() {
-        let mut map_instances = Vec::<MapInstance>::new();
-        for (data_id, map_id, transform) in maps.iter() {
-            map_instances.push(
-                MapInstance{
-                    command: orders::CreateMapCommand{
-                        data_id: data_id.clone(),
-                        map_id: *map_id,
-                        x: transform.translation.x,
-                        y: transform.translation.z,
-                    }
-                }
-            )
-        }
-        let mut token_instances = Vec::<TokenInstance>::new();
-        for (data_id, token_id, transform) in tokens.iter() {
-            token_instances.push(
-                TokenInstance{
-                    command: orders::CreateTokenCommand{
-                        load_identifier: data_id.clone(),
-                        id: *token_id,
-                        x: transform.translation.x,
-                        y: transform.translation.z,
-                    }
-                }
-            )
-        }
-        let enc = Encounter{
-            map_instances,
-            token_instances,
-        };
+        let enc = build_encounter(&maps, &tokens, &initiative, &lighting);
         let enc_data = serde_json::to_vec(&enc).expect("Unable to serialize encounter data");
         let load_identifier = bank.store_at_id(&current_encounter.0, enc_data.into());
         
@@ -135,10 +247,75 @@ fn save_encounter(
     }
 }
 
+//Everything about a token on the board that gets saved
+type TokenInstances<'w, 's> = Query<'w, 's, (&'static fileload::LoadIdentifier, &'static TokenId, &'static Transform, &'static TokenOwner, &'static TokenLock, Option<&'static TokenHealth>, &'static TokenConditions)>;
+
+fn build_encounter(
+    maps: &Query<(&fileload::LoadIdentifier, &MapId, &Transform, &MapDoors, &MapFog)>,
+    tokens: &TokenInstances,
+    initiative: &Initiative,
+    lighting: &Lighting,
+) -> Encounter {
+    let mut map_instances = Vec::<MapInstance>::new();
+    for (data_id, map_id, transform, doors, fog) in maps.iter() {
+        map_instances.push(
+            MapInstance{
+                command: orders::CreateMapCommand{
+                    data_id: data_id.clone(),
+                    map_id: *map_id,
+                    x: transform.translation.x,
+                    y: transform.translation.z,
+                    doors: doors.0.clone(),
+                    fog: Some(fog.to_state()),
+                }
+            }
+        )
+    }
+    let mut token_instances = Vec::<TokenInstance>::new();
+    for (data_id, token_id, transform, owner, lock, health, conditions) in tokens.iter() {
+        token_instances.push(
+            TokenInstance{
+                command: orders::CreateTokenCommand{
+                    load_identifier: data_id.clone(),
+                    id: *token_id,
+                    x: transform.translation.x,
+                    y: transform.translation.z,
+                    owned_by: owner.0.clone(),
+                    locked: lock.0,
+                    health: health.copied(),
+                    conditions: conditions.clone(),
+                }
+            }
+        )
+    }
+    Encounter{
+        map_instances,
+        token_instances,
+        initiative: initiative.clone(),
+        dynamic_lighting: lighting.chosen.then_some(lighting.dynamic),
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Encounter {
     pub map_instances: Vec<MapInstance>,
     pub token_instances: Vec<TokenInstance>,
+    #[serde(default)]
+    pub initiative: Initiative,
+    //Only set once the GM has picked, otherwise each map starts how it was made
+    #[serde(default)]
+    pub dynamic_lighting: Option<bool>,
+}
+
+impl Encounter {
+    pub fn refresh_hashes(&mut self, mut refresh: impl FnMut(&mut fileload::LoadIdentifier)) {
+        for map in self.map_instances.iter_mut() {
+            refresh(&mut map.command.data_id);
+        }
+        for token in self.token_instances.iter_mut() {
+            refresh(&mut token.command.load_identifier);
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -150,3 +327,74 @@ pub struct MapInstance {
 pub struct TokenInstance {
     command: orders::CreateTokenCommand,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot_parts(data_id: bank::DataId, part_bytes: usize) -> Vec<orders::SyncEncounterCommand> {
+        let encounter = Encounter {
+            map_instances: Vec::new(),
+            token_instances: Vec::new(),
+            initiative: Initiative::default(),
+            dynamic_lighting: Some(true),
+        };
+        let data = serde_json::to_vec(&encounter).unwrap();
+        let parts: Vec<&[u8]> = data.chunks(part_bytes).collect();
+        parts.iter().enumerate()
+            .map(|(index, bytes)| orders::SyncEncounterCommand {
+                data_id,
+                part: index as u32,
+                parts: parts.len() as u32,
+                bytes: bytes.to_vec(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parts_put_back_together() {
+        let parts = snapshot_parts(bank::get_new_id(), 8);
+        assert!(parts.len() > 2);
+        let mut buffer = SnapshotBuffer::default();
+        for part in parts[..parts.len() - 1].iter() {
+            assert!(buffer.add(part).is_none());
+        }
+        let encounter = buffer.add(parts.last().unwrap()).expect("Snapshot should be complete");
+        assert_eq!(encounter.dynamic_lighting, Some(true));
+    }
+
+    #[test]
+    fn single_part_snapshot() {
+        let parts = snapshot_parts(bank::get_new_id(), SNAPSHOT_PART_BYTES);
+        assert_eq!(parts.len(), 1);
+        assert!(SnapshotBuffer::default().add(&parts[0]).is_some());
+    }
+
+    #[test]
+    fn missing_part_is_dropped() {
+        let parts = snapshot_parts(bank::get_new_id(), 8);
+        let mut buffer = SnapshotBuffer::default();
+        assert!(buffer.add(&parts[0]).is_none());
+        for part in parts[2..].iter() {
+            assert!(buffer.add(part).is_none());
+        }
+    }
+
+    #[test]
+    fn new_snapshot_replaces_unfinished_one() {
+        let old = snapshot_parts(bank::get_new_id(), 8);
+        let new = snapshot_parts(bank::get_new_id(), 8);
+        let mut buffer = SnapshotBuffer::default();
+        assert!(buffer.add(&old[0]).is_none());
+        assert!(buffer.add(&old[1]).is_none());
+        //Parts of the old one that turn up late don't get mixed in
+        let mut result = None;
+        for (index, part) in new.iter().enumerate() {
+            if index == 1 {
+                assert!(buffer.add(&old[2]).is_none());
+            }
+            result = buffer.add(part);
+        }
+        assert!(result.is_some());
+    }
+}
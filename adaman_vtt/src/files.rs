@@ -6,15 +6,19 @@ use std::sync::Arc;
 
 use crate::bank;
 use crate::fileload;
+use crate::encounters;
 
 pub struct FilesPlugin;
 
 impl Plugin for FilesPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(Startup, check_for_main.after(bank::setup_bank))
+            .add_systems(Startup, migrate_hashes.after(check_for_main))
             .add_systems(Update, register_token)
             .add_event::<RegisterToken>()
             .add_event::<TokenListUpdated>()
+            .add_systems(Update, replace_token)
+            .add_event::<ReplaceToken>()
             .add_systems(Update, register_map)
             .add_event::<RegisterMap>()
             .add_event::<MapListUpdated>()
@@ -29,6 +33,11 @@ pub const CAMPAIGNS_ID: bank::DataId = bank::DataId(uuid!("00000000-0000-0000-00
 pub const TOKENS_ID: bank::DataId = bank::DataId(uuid!("00000000-0000-0000-0000-ffffff000001"));
 pub const ENCOUNTER_ID: bank::DataId = bank::DataId(uuid!("00000000-0000-0000-0000-ffffff000003"));
 pub const MAPS_ID: bank::DataId = bank::DataId(uuid!("00000000-0000-0000-0000-ffffff000002"));
+pub const JOIN_SETTINGS_ID: bank::DataId = bank::DataId(uuid!("00000000-0000-0000-0000-ffffff000004"));
+//Mixed with an encounter's id to get where that encounter's explored areas are kept
+pub const EXPLORED_ID: bank::DataId = bank::DataId(uuid!("00000000-0000-0000-0000-ffffff000005"));
+//Set once the saved identifiers have been moved over to the FNV-1a hashes
+pub const HASHES_MIGRATED_ID: bank::DataId = bank::DataId(uuid!("00000000-0000-0000-0000-ffffff000006"));
 
 fn check_for_main(
     mut bank: ResMut<bank::Bank>,
@@ -52,6 +61,47 @@ fn check_for_main(
     }
 }
 
+//Everything saved before the hash changed still has the old hashes, and peers would throw the downloads away as corrupted
+fn migrate_hashes(
+    mut bank: ResMut<bank::Bank>,
+) {
+    if bank.contains_data(&HASHES_MIGRATED_ID) {
+        return;
+    }
+    let refresh = |bank: &bank::Bank, id: &mut fileload::LoadIdentifier| {
+        if let Some(fresh) = bank.identify(&id.data_id) {
+            *id = fresh;
+        }
+    };
+
+    let mut maps = bank.get_map_list();
+    for map in maps.maps.iter_mut() {
+        refresh(&*bank, &mut map.load_identifier);
+    }
+    bank.store_at_id(&MAPS_ID, Arc::new(serde_json::to_vec(&maps).ok().unwrap()));
+
+    let mut tokens = bank.get_token_list();
+    for token in tokens.tokens.iter_mut() {
+        refresh(&*bank, &mut token.load_identifier);
+    }
+    bank.store_at_id(&TOKENS_ID, Arc::new(serde_json::to_vec(&tokens).ok().unwrap()));
+
+    //Encounters point at their maps and tokens too, so those are fixed before the encounter itself is hashed again
+    let mut encounters = bank.get_encounter_list();
+    for encounter in encounters.encounters.iter_mut() {
+        let id = encounter.load_identifier.data_id;
+        let Some(mut data) = bank.request_data(&id)
+            .and_then(|data| serde_json::from_slice::<encounters::Encounter>(data.as_slice()).ok()) else {
+            continue;
+        };
+        data.refresh_hashes(|load_identifier| refresh(&*bank, load_identifier));
+        encounter.load_identifier = bank.store_at_id(&id, Arc::new(serde_json::to_vec(&data).ok().unwrap()));
+    }
+    bank.store_at_id(&ENCOUNTER_ID, Arc::new(serde_json::to_vec(&encounters).ok().unwrap()));
+
+    bank.store_at_id(&HASHES_MIGRATED_ID, Arc::new(Vec::new()));
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct MainMenu {
     pub campaigns: Vec<bank::DataId>,
@@ -196,6 +246,34 @@ pub fn register_token(
     }
 }
 
+//Point a library entry at a new version of its token
+#[derive(Event)]
+pub struct ReplaceToken {
+    pub old: fileload::LoadIdentifier,
+    pub name: String,
+    pub load_identifier: fileload::LoadIdentifier,
+}
+
+pub fn replace_token(
+    mut bank: ResMut<bank::Bank>,
+    mut events: EventReader<ReplaceToken>,
+    mut update_event: EventWriter<TokenListUpdated>,
+) {
+    for ev in events.read() {
+        let mut tokens = bank.get_token_list();
+        for token in tokens.tokens.iter_mut() {
+            if token.load_identifier.data_id == ev.old.data_id {
+                token.name = ev.name.clone();
+                token.load_identifier = ev.load_identifier.clone();
+            }
+        }
+        let tokens = Arc::new(serde_json::to_vec(&tokens).ok().unwrap());
+        bank.store_at_id(&TOKENS_ID, tokens);
+
+        update_event.send(TokenListUpdated);
+    }
+}
+
 #[derive(Event)]
 pub struct RegisterEncounter {
     pub name: String,
@@ -1,6 +1,6 @@
 use bevy::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::VecDeque;
+use std::collections::{HashSet, VecDeque};
 use bevy_matchbox::prelude::*;
 use std::cmp::min;
 use std::sync::Arc;
@@ -30,6 +30,7 @@ impl Plugin for FileTransfer {
 
             .add_event::<DataRequest>()
             .add_systems(Update, recieve_data_request)
+            .add_systems(Update, serve_data_requests.after(recieve_data_request))
 
             .add_event::<IncomingDownload>()
             .add_systems(Update, recieve_data)
@@ -44,9 +45,13 @@ impl Plugin for FileTransfer {
 
             .add_systems(Update, send_available_on_connect)
             .add_systems(Update, send_upload_available)
-            
-            .insert_resource(UploadState{state: None})
-            .insert_resource(DownloadState{state: None});
+
+            .add_systems(Update, set_download_window.before(download_file))
+            .add_systems(Update, handle_peer_disconnect.before(download_file))
+            .add_systems(Update, check_download_timeouts.before(download_file))
+
+            .insert_resource(UploadState::new())
+            .insert_resource(DownloadState::new());
     }
 }
 
@@ -72,20 +77,32 @@ pub fn handle_load_queue(
     mut upload_requests: EventWriter<SendUploadRequest>,
     mut events: EventWriter<crate::ui::InsertLog>,
 ) {
-    match download.state {
-        None => {
-            if let Some(new_download) = queue.queue.pop_front() {
-                upload_requests.send(SendUploadRequest{
-                    recipient: networking::RecepientPeer::All,
-                    load_id: new_download.id.clone(),
-                });
-                let size = new_download.id.size;
-                
-                events.send(crate::ui::InsertLog::new(format!("Downloading file: {size}")));
-                download.state = Some(FileDownload::new(new_download));
-            }
-        },
-        Some(_) => (),
+    //Anything waiting on a file that's already downloading just rides along with it
+    let mut waiting = VecDeque::<fileload::LoadRequest>::new();
+    while let Some(request) = queue.queue.pop_front() {
+        match download.get_mut(&request.id.data_id) {
+            Some(existing) => existing.requests.push(request),
+            None => waiting.push_back(request),
+        }
+    }
+    queue.queue = waiting;
+
+    while download.downloads.len() < download.window {
+        let Some(new_download) = queue.queue.pop_front() else {
+            break;
+        };
+        if let Some(existing) = download.get_mut(&new_download.id.data_id) {
+            existing.requests.push(new_download);
+            continue;
+        }
+        upload_requests.send(SendUploadRequest{
+            recipient: networking::RecepientPeer::All,
+            load_id: new_download.id.clone(),
+        });
+        let size = new_download.id.size;
+
+        events.send(crate::ui::InsertLog::new(format!("Downloading file: {size}")));
+        download.downloads.push(FileDownload::new(new_download));
     }
 }
 
@@ -117,46 +134,111 @@ pub fn send_upload_requests(
     }
 }
 
+//How many files we download at the same time, until the join settings say otherwise
+pub const DOWNLOAD_WINDOW: usize = 3;
+
 #[derive(Resource)]
 pub struct DownloadState{
-    pub state: Option<FileDownload>,
+    pub downloads: Vec<FileDownload>,
+    pub window: usize,
+}
+
+impl DownloadState {
+    fn new() -> DownloadState {
+        DownloadState {
+            downloads: Vec::new(),
+            window: DOWNLOAD_WINDOW,
+        }
+    }
+
+    fn get_mut(&mut self, data_id: &bank::DataId) -> Option<&mut FileDownload> {
+        self.downloads.iter_mut().find(|x| x.id.data_id == *data_id)
+    }
+}
+
+fn set_download_window(
+    mut download: ResMut<DownloadState>,
+    mut ev_join: EventReader<networking::JoinRoom>,
+) {
+    for ev in ev_join.read() {
+        download.window = ev.settings.download_window.max(1);
+    }
 }
 
 pub struct FileDownload{
-    request: fileload::LoadRequest,
+    id: fileload::LoadIdentifier,
+    //Everything waiting on this file, loaded once it's done
+    requests: Vec<fileload::LoadRequest>,
     peers: Vec<UploadingPeer>,
     sections: Vec<DataSectionIdentifier>,
     data: Vec<u8>,
+    //How long we've gone without anyone uploading to us
+    waiting: f32,
+    lock_attempts: u32,
+    //Last progress shown in the log, so it's only shown again once it moves
+    reported: u32,
 }
 
 const REQUEST_BYTES: usize = 16 * 1024;
+//How far a download gets between progress messages, in percent
+const PROGRESS_STEP: u32 = 25;
+//Seconds a peer has to answer a section request before it's given to someone else
+const SECTION_TIMEOUT: f32 = 10.;
+//Seconds between asking everyone for the file again when nobody is uploading
+const LOCK_RETRY: f32 = 5.;
+//How many times we ask for the file before giving up on it
+const MAX_LOCK_ATTEMPTS: u32 = 4;
 
 impl FileDownload{
     fn new(value: fileload::LoadRequest) -> Self {
+        FileDownload{
+            sections: Self::build_sections(&value.id),
+            data: vec![0; value.id.size],
+            id: value.id.clone(),
+            requests: vec![value],
+            peers: Vec::<UploadingPeer>::new(),
+            waiting: 0.,
+            lock_attempts: 1,
+            reported: 0,
+        }
+    }
+
+    fn build_sections(id: &fileload::LoadIdentifier) -> Vec<DataSectionIdentifier> {
         let mut sections = Vec::<DataSectionIdentifier>::new();
 
-        for i in 0..=(value.id.size / REQUEST_BYTES) {
+        for i in 0..=(id.size / REQUEST_BYTES) {
             let start = i * REQUEST_BYTES;
-            let end = min(start + REQUEST_BYTES, value.id.size);
+            let end = min(start + REQUEST_BYTES, id.size);
             sections.push(DataSectionIdentifier{
                 index: i,
                 start,
                 end,
-                data_id: value.id.data_id,
+                data_id: id.data_id,
             })
         }
+        sections
+    }
 
-        let mut data = Vec::<u8>::with_capacity(value.id.size);
-        for _i in 0..value.id.size {
-            data.push(0);
-        }
-        
-        FileDownload{
-            request: value,
-            peers: Vec::<UploadingPeer>::new(),
-            data,
-            sections,
+    //Throw away everything downloaded so far and start again from new peers
+    fn restart(&mut self) -> Vec<UploadingPeer> {
+        self.sections = Self::build_sections(&self.id);
+        self.data = vec![0; self.id.size];
+        self.waiting = 0.;
+        self.lock_attempts += 1;
+        self.reported = 0;
+        std::mem::take(&mut self.peers)
+    }
+
+    //Put a peer's outstanding section back in the queue and forget about them
+    fn drop_peer(&mut self, peer_id: &PeerId) -> bool {
+        let Some(index) = self.peers.iter().position(|peer| peer.id == *peer_id) else {
+            return false;
+        };
+        let peer = self.peers.remove(index);
+        if let Some(section) = peer.current_request {
+            self.sections.push(section);
         }
+        true
     }
 
     fn is_done(&self) -> bool {
@@ -176,12 +258,21 @@ impl FileDownload{
 struct UploadingPeer{
     id: PeerId,
     current_request: Option<DataSectionIdentifier>,
+    request_time: f32,
 }
 
 #[derive(Event, Serialize, Deserialize, Clone)]
 pub struct DownloadedSection {
     pub data: Vec<u8>,
     pub id: DataSectionIdentifier,
+    pub hash: u64,
+}
+
+impl DownloadedSection {
+    fn is_valid(&self) -> bool {
+        self.data.len() == self.id.end - self.id.start
+            && bank::hash_data(&self.data) == self.hash
+    }
 }
 
 #[derive(Event, Serialize, Deserialize, Clone)]
@@ -198,100 +289,154 @@ pub fn complete_download(
     mut ev_networked: EventWriter<networking::NetworkedCommandEvent>,
     mut bank: ResMut<bank::Bank>,
     mut ev_send_upload_available: EventWriter<SendUploadAvailable>,
+    mut upload_requests: EventWriter<SendUploadRequest>,
     mut events: EventWriter<crate::ui::InsertLog>,
+    local_peer_id: Option<Res<networking::LocalPeerId>>,
 ) {
-    //If there is a FileDownload
-    let state = match &download.state {
-        None => return,
-        Some(x) => x, 
+    let Some(local_peer_id) = local_peer_id else {
+        return
     };
-    
-    let downloaded = 100. - ((state.sections.len() as f32 / (state.request.id.size as f32 / REQUEST_BYTES as f32)) * 100.).round();
-    let downloaded = format!("Downloaded: {}%", downloaded.to_string());
-    events.send(crate::ui::InsertLog::new(downloaded));
+    let mut finished = Vec::<bank::DataId>::new();
+    for state in download.downloads.iter_mut() {
+        let downloaded = 100. - ((state.sections.len() as f32 / (state.id.size as f32 / REQUEST_BYTES as f32)) * 100.).round();
+        //Only every quarter of the way, big files would otherwise fill the log
+        let step = (downloaded.max(0.) as u32 / PROGRESS_STEP) * PROGRESS_STEP;
+        if step > state.reported {
+            state.reported = step;
+            events.send(crate::ui::InsertLog::new(format!("Downloaded: {step}%")));
+        }
 
-    if state.is_done() {
-        let _ = bank.store_at_id(&state.request.id.data_id, state.data.clone().into());
-        ev_load.send(state.request.clone());
+        if !state.is_done() {
+            continue;
+        }
 
-        for peer in state.peers.iter() {
-            ev_networked.send(
-                networking::NetworkedCommandEvent{
-                    reliability: networking::NetworkReliability::Reliable,
-                    peer_id: networking::RecepientPeer::Peer(peer.id),
-                    order: orders::OrderEvent{
-                        command: orders::Command::UnlockUpload(
-                            orders::UnlockUploadCommand
-                        )
-                    }
-                }
-            );
+        //Make sure we got the file we asked for before anything tries to use it
+        if bank::hash_data(&state.data) != state.id.hash {
+            for peer in state.restart() {
+                send_unlock(peer.id, state.id.data_id, &local_peer_id, &mut ev_networked);
+            }
+            if state.lock_attempts > MAX_LOCK_ATTEMPTS {
+                events.send(crate::ui::InsertLog::new("Download failed, the file was corrupted".to_string()));
+                finished.push(state.id.data_id);
+                continue;
+            }
+            events.send(crate::ui::InsertLog::new("Downloaded file was corrupted, retrying".to_string()));
+            upload_requests.send(SendUploadRequest{
+                recipient: networking::RecepientPeer::All,
+                load_id: state.id.clone(),
+            });
+            continue;
         }
 
-        ev_send_upload_available.send(SendUploadAvailable);
+        let _ = bank.store_at_id(&state.id.data_id, state.data.clone().into());
+        for request in state.requests.iter() {
+            ev_load.send(request.clone());
+        }
+
+        for peer in state.peers.iter() {
+            send_unlock(peer.id, state.id.data_id, &local_peer_id, &mut ev_networked);
+        }
 
         println!("Download Complete");
 
         events.send(crate::ui::InsertLog::new("Download Complete".to_string()));
 
-        download.state = None;
+        finished.push(state.id.data_id);
     }
+
+    if !finished.is_empty() {
+        download.downloads.retain(|x| !finished.contains(&x.id.data_id));
+        ev_send_upload_available.send(SendUploadAvailable);
+    }
+}
+
+//Tell an uploader we don't need them for this file anymore
+fn send_unlock(
+    peer_id: PeerId,
+    data_id: bank::DataId,
+    local_peer_id: &networking::LocalPeerId,
+    ev_networked: &mut EventWriter<networking::NetworkedCommandEvent>,
+) {
+    ev_networked.send(
+        networking::NetworkedCommandEvent{
+            reliability: networking::NetworkReliability::Reliable,
+            peer_id: networking::RecepientPeer::Peer(peer_id),
+            order: orders::OrderEvent{
+                command: orders::Command::UnlockUpload(
+                    orders::UnlockUploadCommand{
+                        peer_id: local_peer_id.id,
+                        data_id,
+                    }
+                )
+            }
+        }
+    );
 }
 
 pub fn download_file(
     mut download: ResMut<DownloadState>,
     mut ev_networked: EventWriter<networking::NetworkedCommandEvent>,
     local_peer_id: Option<Res<networking::LocalPeerId>>,
+    time: Res<Time>,
 ) {
-    //If there is a FileDownload
-    let download = match &mut download.state {
-        None => return,
-        Some(x) => x, 
-    };
-
     let Some(local_peer_id) = local_peer_id else {
         return;
     };
 
-    //If there are any peers that aren't pending data
-    for peer in download.peers.iter_mut() {
-        if peer.current_request.is_none() && !download.sections.is_empty() { 
-            let Some(section) = download.sections.pop() else {
-                continue;
-            };
-            peer.current_request = Some(section.clone());
-            //Request part of file
-            //Request a section that isn't pending (unless there are no unloaded sections)
-            ev_networked.send(networking::NetworkedCommandEvent{
-                order: orders::OrderEvent{
-                    command: orders::Command::RequestData(orders::RequestDataCommand{
-                        section,
-                        peer_id: local_peer_id.id,
-                    })
-                },
-                reliability: networking::NetworkReliability::Reliable,
-                peer_id: networking::RecepientPeer::Peer(peer.id),
-            })
+    for download in download.downloads.iter_mut() {
+        //If there are any peers that aren't pending data
+        for peer in download.peers.iter_mut() {
+            if peer.current_request.is_none() && !download.sections.is_empty() {
+                let Some(section) = download.sections.pop() else {
+                    continue;
+                };
+                peer.current_request = Some(section.clone());
+                peer.request_time = time.elapsed_seconds();
+                //Request part of file
+                //Request a section that isn't pending (unless there are no unloaded sections)
+                ev_networked.send(networking::NetworkedCommandEvent{
+                    order: orders::OrderEvent{
+                        command: orders::Command::RequestData(orders::RequestDataCommand{
+                            section,
+                            peer_id: local_peer_id.id,
+                        })
+                    },
+                    reliability: networking::NetworkReliability::Reliable,
+                    peer_id: networking::RecepientPeer::Peer(peer.id),
+                })
+            }
         }
-    } 
+    }
 }
 
 #[derive(Event)]
 pub struct SuccessfulUploadLock{
     pub peer_id: PeerId,
+    pub data_id: bank::DataId,
 }
 
 pub fn recieve_successful_lock(
     mut ev_successful_upload_lock: EventReader<SuccessfulUploadLock>,
     mut download: ResMut<DownloadState>,
+    mut ev_networked: EventWriter<networking::NetworkedCommandEvent>,
+    local_peer_id: Option<Res<networking::LocalPeerId>>,
 ) {
-    let Some(ref mut download) = download.state else {
+    let Some(local_peer_id) = local_peer_id else {
         return
     };
     for ev in ev_successful_upload_lock.read() {
+        //They locked for a download we've already finished or given up on
+        let Some(download) = download.get_mut(&ev.data_id) else {
+            send_unlock(ev.peer_id, ev.data_id, &local_peer_id, &mut ev_networked);
+            continue;
+        };
+        if download.peers.iter().any(|peer| peer.id == ev.peer_id) {
+            continue;
+        }
         download.peers.push(UploadingPeer{
             id: ev.peer_id,
             current_request: None,
+            request_time: 0.,
         });
     }
 }
@@ -314,96 +459,149 @@ pub fn lock_upload(
         return
     };
     for ev in ev_upload_request.read() {
-        if upload_state.state.is_none() {
-            let Some(file_data) = bank.request_data(&ev.load_id.data_id) else {
+        let data_id = ev.load_id.data_id;
+        let already_locked = upload_state.get(&ev.peer_id, &data_id).is_some();
+        if !already_locked {
+            if upload_state.uploads.len() >= upload_state.max_uploads {
+                continue;
+            }
+            let Some(file_data) = bank.request_data(&data_id) else {
                 continue;
             };
 
             let msg = format!("Upload locked to {}", &ev.peer_id);
             events.send(crate::ui::InsertLog::new(msg));
 
-            upload_state.state = Some(FileUpload{
+            upload_state.uploads.push(FileUpload{
                 target_peer_id: ev.peer_id,
+                data_id,
                 file: file_data.clone(),
             });
+        }
 
-            ev_networked.send(
-                networking::NetworkedCommandEvent{
-                    peer_id: networking::RecepientPeer::Peer(ev.peer_id),
-                    reliability: networking::NetworkReliability::Reliable,
-                    order: orders::OrderEvent{
-                        command: orders::Command::SuccessfulUploadLock(
-                            orders::SuccessfulUploadLockedCommand{
-                                peer_id: local_peer_id.id,
-                            }
-                        )
-                    }
+        ev_networked.send(
+            networking::NetworkedCommandEvent{
+                peer_id: networking::RecepientPeer::Peer(ev.peer_id),
+                reliability: networking::NetworkReliability::Reliable,
+                order: orders::OrderEvent{
+                    command: orders::Command::SuccessfulUploadLock(
+                        orders::SuccessfulUploadLockedCommand{
+                            peer_id: local_peer_id.id,
+                            data_id,
+                        }
+                    )
                 }
-            )
-        }
+            }
+        )
     }
 }
 
+//How many peers we upload to at the same time
+const MAX_UPLOADS: usize = 4;
+//How many sections we send out each frame, shared between everyone we're uploading to
+const SECTIONS_PER_FRAME: usize = 8;
+
 #[derive(Resource)]
 pub struct UploadState{
-    state: Option<FileUpload>,
+    uploads: Vec<FileUpload>,
+    pending: VecDeque<DataRequest>,
+    pub max_uploads: usize,
+}
+
+impl UploadState {
+    fn new() -> UploadState {
+        UploadState {
+            uploads: Vec::new(),
+            pending: VecDeque::new(),
+            max_uploads: MAX_UPLOADS,
+        }
+    }
+
+    fn get(&self, peer_id: &PeerId, data_id: &bank::DataId) -> Option<&FileUpload> {
+        self.uploads.iter().find(|x| x.target_peer_id == *peer_id && x.data_id == *data_id)
+    }
+
+    fn has_capacity(&self) -> bool {
+        self.uploads.len() < self.max_uploads
+    }
 }
 
 pub struct FileUpload{
     target_peer_id: PeerId,
+    data_id: bank::DataId,
     file: Arc<Vec<u8>>,
 }
 
-#[derive(Event)]
+#[derive(Event, Clone)]
 pub struct DataRequest{
     pub peer_id: PeerId,
     pub section: DataSectionIdentifier,
 }
 
 pub fn recieve_data_request(
-    upload_state: ResMut<UploadState>,
+    mut upload_state: ResMut<UploadState>,
     mut ev_data_request: EventReader<DataRequest>,
-    mut ev_networked: EventWriter<networking::NetworkedCommandEvent>,
-    local_peer_id: Option<Res<networking::LocalPeerId>>
 ){
     for ev in ev_data_request.read() {
-        let Some(ref upload) = upload_state.state else {
-            println!("No upload state");
-            return;
-        };
-
-        let Some(ref local_peer_id) = local_peer_id else {
-            return;
-        };
-
-        if ev.peer_id != upload.target_peer_id {
+        if upload_state.get(&ev.peer_id, &ev.section.data_id).is_none() {
             let wrong_peer_id = ev.peer_id;
-            let correct_peer_id = upload.target_peer_id;
-            println!("Data request from wrong peer id: {wrong_peer_id} Locked to: {correct_peer_id}");
+            println!("Data request from peer we aren't locked to: {wrong_peer_id}");
+            continue;
         }
+        upload_state.pending.push_back(ev.clone());
+    }
+}
 
-        let Some(data) = upload.file.get(ev.section.start..ev.section.end) else {
-            println!("requested data outside bounds. File Size: {:?} : Requested: {:?} - {:?} : Index: {:?}", upload.file.len(), ev.section.start, ev.section.end, ev.section.index);
-            continue;
-        };
-        ev_networked.send(
-            networking::NetworkedCommandEvent{
-                peer_id: networking::RecepientPeer::Peer(ev.peer_id),
-                reliability: networking::NetworkReliability::Reliable,
-                order: orders::OrderEvent{
-                    command: orders::Command::RecieveData(
-                        orders::RecieveDataCommand{
-                            peer_id: local_peer_id.id,
-                            data: DownloadedSection {
-                                data: data.to_vec(),
-                                id: ev.section.clone(),
-                            },
-                        }
-                    )
+//Answer requests round robin so one peer can't starve the others
+pub fn serve_data_requests(
+    mut upload_state: ResMut<UploadState>,
+    mut ev_networked: EventWriter<networking::NetworkedCommandEvent>,
+    local_peer_id: Option<Res<networking::LocalPeerId>>
+){
+    let Some(ref local_peer_id) = local_peer_id else {
+        return;
+    };
 
-                }
+    let mut budget = SECTIONS_PER_FRAME;
+    while budget > 0 && !upload_state.pending.is_empty() {
+        let mut served = HashSet::<PeerId>::new();
+        let mut remaining = VecDeque::<DataRequest>::new();
+        while let Some(request) = upload_state.pending.pop_front() {
+            if budget == 0 || served.contains(&request.peer_id) {
+                remaining.push_back(request);
+                continue;
             }
-        );
+            served.insert(request.peer_id);
+            budget -= 1;
+
+            let Some(upload) = upload_state.get(&request.peer_id, &request.section.data_id) else {
+                continue;
+            };
+            let Some(data) = upload.file.get(request.section.start..request.section.end) else {
+                println!("requested data outside bounds. File Size: {:?} : Requested: {:?} - {:?} : Index: {:?}", upload.file.len(), request.section.start, request.section.end, request.section.index);
+                continue;
+            };
+            ev_networked.send(
+                networking::NetworkedCommandEvent{
+                    peer_id: networking::RecepientPeer::Peer(request.peer_id),
+                    reliability: networking::NetworkReliability::Reliable,
+                    order: orders::OrderEvent{
+                        command: orders::Command::RecieveData(
+                            orders::RecieveDataCommand{
+                                peer_id: local_peer_id.id,
+                                data: DownloadedSection {
+                                    hash: bank::hash_data(data),
+                                    data: data.to_vec(),
+                                    id: request.section.clone(),
+                                },
+                            }
+                        )
+
+                    }
+                }
+            );
+        }
+        upload_state.pending = remaining;
     }
 }
 
@@ -416,38 +614,66 @@ pub struct IncomingDownload{
 pub fn recieve_data(
     mut download: ResMut<DownloadState>,
     mut ev_incoming_downloads: EventReader<IncomingDownload>,
+    mut ev_networked: EventWriter<networking::NetworkedCommandEvent>,
+    local_peer_id: Option<Res<networking::LocalPeerId>>,
 ) {
+    let Some(local_peer_id) = local_peer_id else {
+        return
+    };
     for ev in ev_incoming_downloads.read() {
-        let Some(ref mut download) = download.state else {
+        let data_id = ev.downloaded_section.id.data_id;
+        let Some(download) = download.get_mut(&data_id) else {
             println!("Recieved incoming data with no download");
-            return;
+            continue;
         };
 
+        //Only take the section we're actually waiting on from that peer, anything else is stale
+        let Some(peer) = download.peers.iter_mut().find(|peer| peer.id == ev.peer_id) else {
+            println!("Recieved data from a peer that isn't uploading to us");
+            continue;
+        };
+        let expected = peer.current_request.as_ref().is_some_and(|section| {
+            section.index == ev.downloaded_section.id.index
+                && section.start == ev.downloaded_section.id.start
+                && section.end == ev.downloaded_section.id.end
+        });
+        if !expected {
+            println!("Recieved unexpected section {}", ev.downloaded_section.id.index);
+            continue;
+        }
+        //A bad section goes back in the queue for someone else to send
+        if !ev.downloaded_section.is_valid() {
+            println!("Recieved corrupted section {} from {}", ev.downloaded_section.id.index, ev.peer_id);
+            download.drop_peer(&ev.peer_id);
+            send_unlock(ev.peer_id, data_id, &local_peer_id, &mut ev_networked);
+            continue;
+        }
+        //Remove the request from that peer, as it's done
+        peer.current_request = None;
+
         for i in ev.downloaded_section.id.start.. ev.downloaded_section.id.end {
             let data_index = i - ev.downloaded_section.id.start;
             let _ = std::mem::replace(&mut download.data[i], ev.downloaded_section.data[data_index]);
         }
-        //For each incoming set of data
-        for peer in download.peers.iter_mut() {
-            //Remove the request from that peer, as it's done
-            if peer.id == ev.peer_id {
-                peer.current_request = None;
-            }
-        }
     }
 }
 
 #[derive(Event)]
-pub struct UnlockUpload;
+pub struct UnlockUpload{
+    pub peer_id: PeerId,
+    pub data_id: bank::DataId,
+}
 
 pub fn unlock_upload(
     mut ev_download_complete: EventReader<UnlockUpload>,
     mut ev_send_upload_available: EventWriter<SendUploadAvailable>,
     mut upload: ResMut<UploadState>,
 ){
-    for _ev in ev_download_complete.read() {
-        if upload.state.is_some() {
-            upload.state = None;
+    for ev in ev_download_complete.read() {
+        let before = upload.uploads.len();
+        upload.uploads.retain(|x| !(x.target_peer_id == ev.peer_id && x.data_id == ev.data_id));
+        upload.pending.retain(|x| !(x.peer_id == ev.peer_id && x.section.data_id == ev.data_id));
+        if upload.uploads.len() != before {
             ev_send_upload_available.send(SendUploadAvailable);
         }
     }
@@ -457,7 +683,7 @@ fn send_available_on_connect(
     mut ev_send_upload_available: EventWriter<SendUploadAvailable>,
     mut ev_connected: EventReader<networking::PeerConnected>,
 ) {
-    for ev in ev_connected.read() {
+    for _ev in ev_connected.read() {
         ev_send_upload_available.send(SendUploadAvailable);
     }
 }
@@ -474,8 +700,8 @@ pub fn send_upload_available (
     let Some(local_peer_id) = local_peer_id else {
         return;
     };
-    
-    if upload_state.state.is_some() {
+
+    if !upload_state.has_capacity() {
         return;
     }
 
@@ -507,13 +733,87 @@ pub fn acknowledge_upload_available(
     download: Res<DownloadState>,
 ) {
     for ev in ev_upload_available.read() {
-        if let Some(download) = &download.state {
+        for download in download.downloads.iter() {
+            if download.peers.iter().any(|peer| peer.id == ev.peer_id) {
+                continue;
+            }
             ev_send_upload_request.send(
                 SendUploadRequest {
-                    load_id: download.request.id.clone(),
+                    load_id: download.id.clone(),
                     recipient: networking::RecepientPeer::Peer(ev.peer_id),
                 }
             )
         }
     }
 }
+
+fn handle_peer_disconnect(
+    mut ev_disconnected: EventReader<networking::PeerDisconnected>,
+    mut download: ResMut<DownloadState>,
+    mut upload: ResMut<UploadState>,
+    mut ev_send_upload_available: EventWriter<SendUploadAvailable>,
+) {
+    for ev in ev_disconnected.read() {
+        //Give the sections they were sending us to someone else
+        for download in download.downloads.iter_mut() {
+            download.drop_peer(&ev.0);
+        }
+        //Stop waiting on a peer that's never coming back
+        let before = upload.uploads.len();
+        upload.uploads.retain(|x| x.target_peer_id != ev.0);
+        upload.pending.retain(|x| x.peer_id != ev.0);
+        if upload.uploads.len() != before {
+            ev_send_upload_available.send(SendUploadAvailable);
+        }
+    }
+}
+
+pub fn check_download_timeouts(
+    mut download: ResMut<DownloadState>,
+    mut upload_requests: EventWriter<SendUploadRequest>,
+    mut ev_networked: EventWriter<networking::NetworkedCommandEvent>,
+    mut events: EventWriter<crate::ui::InsertLog>,
+    time: Res<Time>,
+    local_peer_id: Option<Res<networking::LocalPeerId>>,
+) {
+    let Some(local_peer_id) = local_peer_id else {
+        return
+    };
+    let now = time.elapsed_seconds();
+    let mut failed = Vec::<bank::DataId>::new();
+    for state in download.downloads.iter_mut() {
+        //Drop any peer that's sitting on a section for too long
+        let timed_out: Vec<PeerId> = state.peers.iter()
+            .filter(|peer| peer.current_request.is_some() && now - peer.request_time > SECTION_TIMEOUT)
+            .map(|peer| peer.id)
+            .collect();
+        for peer_id in timed_out {
+            state.drop_peer(&peer_id);
+            events.send(crate::ui::InsertLog::new(format!("Download from {peer_id} timed out")));
+            send_unlock(peer_id, state.id.data_id, &local_peer_id, &mut ev_networked);
+        }
+
+        if !state.peers.is_empty() {
+            state.waiting = 0.;
+            continue;
+        }
+
+        //Nobody is uploading, so keep asking around until we run out of attempts
+        state.waiting += time.delta_seconds();
+        if state.waiting < LOCK_RETRY {
+            continue;
+        }
+        state.waiting = 0.;
+        if state.lock_attempts >= MAX_LOCK_ATTEMPTS {
+            events.send(crate::ui::InsertLog::new("Download failed, no peers have the file".to_string()));
+            failed.push(state.id.data_id);
+            continue;
+        }
+        state.lock_attempts += 1;
+        upload_requests.send(SendUploadRequest{
+            recipient: networking::RecepientPeer::All,
+            load_id: state.id.clone(),
+        });
+    }
+    download.downloads.retain(|x| !failed.contains(&x.id.data_id));
+}
@@ -0,0 +1,377 @@
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use serde::{Deserialize, Serialize};
+
+use crate::input;
+use crate::maps;
+use crate::networking;
+use crate::orders;
+use crate::roles;
+use crate::vision;
+
+pub struct FogPlugin;
+
+impl Plugin for FogPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(FogTool{
+                shape: FogToolShape::None,
+                reveal: true,
+                brush_radius: 5.,
+                start: None,
+                points: Vec::new(),
+            })
+            .add_systems(Update, init_fog)
+            .add_systems(Update, use_fog_tool.before(orders::recieve_orders))
+            .add_systems(Update, draw_fog_tool.after(use_fog_tool))
+        ;
+    }
+}
+
+//Which parts of a map the GM has shown the players
+#[derive(Component, Clone, Default)]
+pub struct MapFog {
+    pub enabled: bool,
+    pub width: u32,
+    pub height: u32,
+    pub revealed: Vec<bool>,
+}
+
+//Fog as it's saved in encounters, runs of hidden and revealed texels starting with hidden
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct FogState {
+    pub enabled: bool,
+    pub width: u32,
+    pub height: u32,
+    pub runs: Vec<u32>,
+}
+
+//Masks are stored as alternating runs of false and true, starting with false
+pub fn encode_runs(texels: &[bool]) -> Vec<u32> {
+    let mut runs = Vec::new();
+    let mut value = false;
+    let mut run = 0;
+    for texel in texels.iter() {
+        if *texel != value {
+            runs.push(run);
+            run = 0;
+            value = !value;
+        }
+        run += 1;
+    }
+    runs.push(run);
+    runs
+}
+
+//None when the runs don't add up to the mask's size, they come from other peers and save files
+pub fn decode_runs(runs: &[u32], len: usize) -> Option<Vec<bool>> {
+    let total: u64 = runs.iter().map(|run| *run as u64).sum();
+    if total != len as u64 {
+        return None;
+    }
+    let mut texels = Vec::with_capacity(len);
+    let mut value = false;
+    for run in runs.iter() {
+        texels.extend(std::iter::repeat(value).take(*run as usize));
+        value = !value;
+    }
+    Some(texels)
+}
+
+impl From<FogState> for MapFog {
+    fn from(state: FogState) -> Self {
+        MapFog {
+            enabled: state.enabled,
+            width: state.width,
+            height: state.height,
+            //Left empty when it doesn't fit, which is all hidden until init_fog sizes it to the map
+            revealed: decode_runs(&state.runs, state.width as usize * state.height as usize).unwrap_or_default(),
+        }
+    }
+}
+
+impl MapFog {
+    pub fn to_state(&self) -> FogState {
+        FogState {
+            enabled: self.enabled,
+            width: self.width,
+            height: self.height,
+            runs: encode_runs(&self.revealed),
+        }
+    }
+
+    pub fn is_hidden(&self, x: u32, y: u32) -> bool {
+        self.enabled && !self.revealed.get((y * self.width + x) as usize).copied().unwrap_or(false)
+    }
+
+    fn apply(&mut self, edit: &FogEdit, dimensions: &maps::MapDimensions, transform: &GlobalTransform) {
+        match edit {
+            FogEdit::Enable(enabled) => self.enabled = *enabled,
+            FogEdit::All{ reveal } => self.revealed.iter_mut().for_each(|texel| *texel = *reveal),
+            FogEdit::Paint{ shape, reveal } => {
+                for y in 0..self.height {
+                    for x in 0..self.width {
+                        if !shape.contains(dimensions.texel_to_world(transform, x, y)) {
+                            continue;
+                        }
+                        if let Some(texel) = self.revealed.get_mut((y * self.width + x) as usize) {
+                            *texel = *reveal;
+                        }
+                    }
+                }
+            },
+        }
+    }
+}
+
+//Shapes are on the ground plane in world space
+#[derive(Serialize, Deserialize, Clone)]
+pub enum FogShape {
+    Rectangle{ min: Vec2, max: Vec2 },
+    Polygon(Vec<Vec2>),
+    Brush{ center: Vec2, radius: f32 },
+}
+
+impl FogShape {
+    fn contains(&self, point: Vec2) -> bool {
+        match self {
+            FogShape::Rectangle{ min, max } => point.cmpge(*min).all() && point.cmple(*max).all(),
+            FogShape::Polygon(points) => vision::contains(points, point),
+            FogShape::Brush{ center, radius } => center.distance(point) <= *radius,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub enum FogEdit {
+    Enable(bool),
+    All{ reveal: bool },
+    Paint{ shape: FogShape, reveal: bool },
+}
+
+//Applies to every map, shapes only touch the maps they're drawn over
+pub fn apply_edit(
+    edit: &FogEdit,
+    maps: &mut Query<(&maps::MapDimensions, &GlobalTransform, &mut MapFog)>,
+) {
+    for (dimensions, transform, mut fog) in maps.iter_mut() {
+        fog.apply(edit, dimensions, transform);
+    }
+}
+
+//Once the map has loaded we know how big the fog has to be
+fn init_fog(
+    mut maps: Query<(&maps::MapDimensions, &mut MapFog), Added<maps::MapDimensions>>,
+) {
+    for (dimensions, mut fog) in maps.iter_mut() {
+        let (width, height) = dimensions.texels();
+        if fog.width != width || fog.height != height || fog.revealed.len() != (width * height) as usize {
+            fog.width = width;
+            fog.height = height;
+            fog.revealed = vec![false; (width * height) as usize];
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum FogToolShape {
+    None,
+    Rectangle,
+    Polygon,
+    Brush,
+}
+
+//What the GM is currently painting fog with
+#[derive(Resource)]
+pub struct FogTool {
+    pub shape: FogToolShape,
+    pub reveal: bool,
+    pub brush_radius: f32,
+    start: Option<Vec2>,
+    points: Vec<Vec2>,
+}
+
+pub fn send_fog_edit(
+    edit: FogEdit,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    ev_client.send(networking::ClientCommandEvent {
+        order: orders::OrderEvent {
+            command: orders::Command::Fog(orders::FogCommand {
+                edit,
+            }),
+        },
+        reliability: networking::NetworkReliability::Reliable,
+    });
+}
+
+fn cursor_on_ground(
+    windows: &Query<&Window>,
+    camera_q: &Query<(&Camera, &GlobalTransform)>,
+) -> Option<Vec2> {
+    let cursor = windows.get_single().ok()?.cursor_position()?;
+    let (camera, camera_transform) = camera_q.get_single().ok()?;
+    let ray = camera.viewport_to_world(camera_transform, cursor)?;
+    let point = input::get_plane_intersection(ray, Vec3::ZERO, Vec3::Y)?;
+    Some(Vec2::new(point.x, point.z))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn use_fog_tool(
+    mut tool: ResMut<FogTool>,
+    roles: Res<roles::Roles>,
+    mouse: Res<Input<MouseButton>>,
+    keys: Res<Input<KeyCode>>,
+    windows: Query<&Window>,
+    camera_q: Query<(&Camera, &GlobalTransform)>,
+    mut contexts: EguiContexts,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+) {
+    if !roles.is_game_master() || tool.shape == FogToolShape::None {
+        return;
+    }
+    //Clicks on the ui aren't meant for the map
+    if contexts.ctx_mut().wants_pointer_input() {
+        return;
+    }
+    let Some(cursor) = cursor_on_ground(&windows, &camera_q) else {
+        return;
+    };
+    let reveal = tool.reveal;
+    match tool.shape {
+        FogToolShape::None => {},
+        FogToolShape::Rectangle => {
+            if mouse.just_pressed(MouseButton::Left) {
+                tool.start = Some(cursor);
+            }
+            if mouse.just_released(MouseButton::Left) {
+                if let Some(start) = tool.start.take() {
+                    send_fog_edit(FogEdit::Paint{
+                        shape: FogShape::Rectangle{ min: start.min(cursor), max: start.max(cursor) },
+                        reveal,
+                    }, &mut ev_client);
+                }
+            }
+        },
+        FogToolShape::Polygon => {
+            if mouse.just_pressed(MouseButton::Left) {
+                tool.points.push(cursor);
+            }
+            //Right click or enter finishes the polygon
+            let finish = mouse.just_pressed(MouseButton::Right) || keys.just_pressed(KeyCode::Return);
+            if finish && tool.points.len() >= 3 {
+                let points = std::mem::take(&mut tool.points);
+                send_fog_edit(FogEdit::Paint{
+                    shape: FogShape::Polygon(points),
+                    reveal,
+                }, &mut ev_client);
+            }
+            if keys.just_pressed(KeyCode::Escape) {
+                tool.points.clear();
+            }
+        },
+        FogToolShape::Brush => {
+            if mouse.just_released(MouseButton::Left) {
+                tool.start = None;
+            }
+            if !mouse.pressed(MouseButton::Left) {
+                return;
+            }
+            //Only stamp again once the brush has moved a bit, so a held click doesn't flood the network
+            let radius = tool.brush_radius;
+            if tool.start.is_some_and(|last| last.distance(cursor) < radius / 2.) {
+                return;
+            }
+            tool.start = Some(cursor);
+            send_fog_edit(FogEdit::Paint{
+                shape: FogShape::Brush{ center: cursor, radius },
+                reveal,
+            }, &mut ev_client);
+        },
+    }
+}
+
+//Just above the fog
+const TOOL_HEIGHT: f32 = 0.05;
+
+fn draw_fog_tool(
+    tool: Res<FogTool>,
+    roles: Res<roles::Roles>,
+    windows: Query<&Window>,
+    camera_q: Query<(&Camera, &GlobalTransform)>,
+    mut gizmos: Gizmos,
+) {
+    if !roles.is_game_master() || tool.shape == FogToolShape::None {
+        return;
+    }
+    let Some(cursor) = cursor_on_ground(&windows, &camera_q) else {
+        return;
+    };
+    let color = if tool.reveal { Color::WHITE } else { Color::GRAY };
+    let to_world = |point: Vec2| Vec3::new(point.x, TOOL_HEIGHT, point.y);
+    match tool.shape {
+        FogToolShape::None => {},
+        FogToolShape::Rectangle => {
+            if let Some(start) = tool.start {
+                let center = (start + cursor) / 2.;
+                gizmos.rect(
+                    to_world(center),
+                    Quat::from_rotation_x(std::f32::consts::FRAC_PI_2),
+                    (cursor - start).abs(),
+                    color,
+                );
+            }
+        },
+        FogToolShape::Polygon => {
+            let mut points: Vec<Vec3> = tool.points.iter().map(|point| to_world(*point)).collect();
+            points.push(to_world(cursor));
+            gizmos.linestrip(points, color);
+        },
+        FogToolShape::Brush => {
+            gizmos.circle(to_world(cursor), Vec3::Y, tool.brush_radius, color);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runs_round_trip() {
+        let masks = [
+            vec![],
+            vec![false, false, true, true, true, false],
+            vec![true, true, false],
+            vec![true; 7],
+            vec![false; 7],
+        ];
+        for mask in masks {
+            let runs = encode_runs(&mask);
+            assert_eq!(decode_runs(&runs, mask.len()), Some(mask));
+        }
+    }
+
+    #[test]
+    fn runs_start_with_hidden() {
+        assert_eq!(encode_runs(&[true, true, false]), vec![0, 2, 1]);
+    }
+
+    #[test]
+    fn runs_that_dont_fit_are_rejected() {
+        assert_eq!(decode_runs(&[2, 2], 5), None);
+        assert_eq!(decode_runs(&[2, 4], 5), None);
+        assert_eq!(decode_runs(&[u32::MAX, u32::MAX], 5), None);
+    }
+
+    #[test]
+    fn bad_state_is_all_hidden() {
+        let fog = MapFog::from(FogState {
+            enabled: true,
+            width: 2,
+            height: 2,
+            runs: vec![1, 100],
+        });
+        assert!(fog.revealed.is_empty());
+        assert!(fog.is_hidden(1, 1));
+    }
+}
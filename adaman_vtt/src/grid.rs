@@ -0,0 +1,124 @@
+use bevy::prelude::*;
+use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy_mod_picking::prelude::*;
+
+use crate::maps;
+
+pub struct GridPlugin;
+
+impl Plugin for GridPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(GridSettings::default())
+            .add_systems(Update, update_grid)
+        ;
+    }
+}
+
+//How the grid overlay looks, local to each user
+#[derive(Resource)]
+pub struct GridSettings {
+    pub visible: bool,
+    pub color: [f32; 3],
+    pub opacity: f32,
+    pub line_width: f32,
+}
+
+impl Default for GridSettings {
+    fn default() -> Self {
+        GridSettings {
+            visible: true,
+            color: [0., 0., 0.],
+            opacity: 0.5,
+            line_width: 1.,
+        }
+    }
+}
+
+//Just above the map and below the vision mask, local +z is towards the camera
+const GRID_HEIGHT: f32 = 0.01;
+//World units per step of the line width setting
+const LINE_WIDTH_SCALE: f32 = 0.05;
+
+//The grid lines over one map, a mesh rather than gizmos so its width doesn't change everyone else's lines
+#[derive(Component)]
+pub struct GridOverlay;
+
+fn update_grid(
+    mut commands: Commands,
+    settings: Res<GridSettings>,
+    new_maps: Query<(Entity, &maps::MapDimensions), Added<maps::MapDimensions>>,
+    maps: Query<(Entity, &maps::MapDimensions)>,
+    overlays: Query<(Entity, &Parent), With<GridOverlay>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let targets: Vec<(Entity, &maps::MapDimensions)> = if settings.is_changed() {
+        maps.iter().collect()
+    } else {
+        new_maps.iter().collect()
+    };
+    let [r, g, b] = settings.color;
+    for (entity, dimensions) in targets {
+        for (overlay, parent) in overlays.iter() {
+            if parent.get() == entity {
+                commands.entity(overlay).despawn_recursive();
+            }
+        }
+        if !settings.visible || dimensions.grid.pixels_per <= 0. {
+            continue;
+        }
+        let overlay = commands.spawn((
+            PbrBundle {
+                mesh: meshes.add(grid_mesh(dimensions, settings.line_width * LINE_WIDTH_SCALE)),
+                material: materials.add(StandardMaterial {
+                    base_color: Color::rgba(r, g, b, settings.opacity),
+                    alpha_mode: AlphaMode::Blend,
+                    unlit: true,
+                    ..default()
+                }),
+                transform: Transform::from_xyz(0., 0., GRID_HEIGHT),
+                ..default()
+            },
+            Pickable::IGNORE,
+            GridOverlay,
+        )).id();
+        commands.entity(entity).push_children(&[overlay]);
+    }
+}
+
+//A thin quad for every line, in the map's local space
+fn grid_mesh(dimensions: &maps::MapDimensions, width: f32) -> Mesh {
+    let grid = &dimensions.grid;
+    //The part of the grid that's actually over the image
+    let (left, top) = grid.pixel_to_grid(0., 0.);
+    let (right, bottom) = grid.pixel_to_grid(dimensions.pixel_width as f32, dimensions.pixel_height as f32);
+
+    let mut lines = Vec::new();
+    let mut x = left.ceil();
+    while x <= right {
+        lines.push((dimensions.grid_to_local(x, top), dimensions.grid_to_local(x, bottom)));
+        x += 1.;
+    }
+    let mut y = top.ceil();
+    while y <= bottom {
+        lines.push((dimensions.grid_to_local(left, y), dimensions.grid_to_local(right, y)));
+        y += 1.;
+    }
+
+    let mut positions = Vec::<[f32; 3]>::new();
+    let mut indices = Vec::<u32>::new();
+    for (start, end) in lines {
+        let along = (end - start).truncate().normalize_or_zero();
+        let side = Vec3::new(-along.y, along.x, 0.) * width / 2.;
+        let first = positions.len() as u32;
+        positions.extend([start + side, start - side, end - side, end + side].map(|point| point.to_array()));
+        indices.extend([first, first + 1, first + 2, first, first + 2, first + 3]);
+    }
+    let normals = vec![[0., 0., 1.]; positions.len()];
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
@@ -0,0 +1,234 @@
+use bevy::prelude::*;
+use bevy_mod_picking::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::networking;
+use crate::orders;
+use crate::roles;
+use crate::tokens;
+
+pub struct HealthPlugin;
+
+impl Plugin for HealthPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, init_health)
+            .add_systems(Update, clamp_health.after(init_health))
+            .add_systems(Update, spawn_health_bars.after(init_health))
+            .add_systems(Update, update_health_bars.after(spawn_health_bars))
+        ;
+    }
+}
+
+//Hit points of one token on the board, the max comes from its data
+#[derive(Component, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct TokenHealth {
+    pub current: i64,
+    pub temp: i64,
+    //Keeps the bar to the GM, for monsters the players shouldn't know the health of
+    pub hidden: bool,
+}
+
+impl TokenHealth {
+    //Temporary hit points soak up damage first
+    pub fn damage(&mut self, amount: i64) {
+        let absorbed = amount.min(self.temp);
+        self.temp -= absorbed;
+        self.current = (self.current - (amount - absorbed)).max(0);
+    }
+
+    pub fn heal(&mut self, amount: i64, max: i64) {
+        self.current = (self.current + amount).min(max);
+    }
+
+    //Temporary hit points don't stack, the higher amount is kept
+    pub fn add_temp(&mut self, amount: i64) {
+        self.temp = self.temp.max(amount);
+    }
+}
+
+pub fn set_health(
+    id: tokens::TokenId,
+    health: TokenHealth,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    ev_client.send(networking::ClientCommandEvent {
+        order: orders::OrderEvent {
+            command: orders::Command::SetHealth(orders::SetHealthCommand {
+                id,
+                health,
+            }),
+        },
+        reliability: networking::NetworkReliability::Reliable,
+    });
+}
+
+//New tokens start at full health once we know what full is
+fn init_health(
+    mut commands: Commands,
+    tokens: Query<(Entity, &tokens::StrippedTokenData, &tokens::TokenOwner), Without<TokenHealth>>,
+) {
+    for (entity, data, owner) in tokens.iter() {
+        commands.entity(entity).insert(TokenHealth {
+            current: data.hit_points,
+            temp: 0,
+            hidden: owner.0.is_none(),
+        });
+    }
+}
+
+//Swapping a token's data can lower its max, every peer does this so there's nothing to send
+fn clamp_health(
+    mut tokens: Query<(&tokens::StrippedTokenData, &mut TokenHealth), Changed<tokens::StrippedTokenData>>,
+) {
+    for (data, mut health) in tokens.iter_mut() {
+        if health.current > data.hit_points {
+            health.current = data.hit_points;
+        }
+    }
+}
+
+#[derive(Component)]
+pub struct HealthBar;
+
+#[derive(Component)]
+pub struct HealthBarFill;
+
+const BAR_HEIGHT: f32 = 0.6;
+//Gap between the top of the token and the bar
+const BAR_GAP: f32 = 0.5;
+
+fn spawn_health_bars(
+    mut commands: Commands,
+    tokens: Query<Entity, Added<TokenHealth>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for entity in tokens.iter() {
+        //Unit quads, stretched to the token's size when they're updated
+        let quad = meshes.add(shape::Quad::new(Vec2::ONE).into());
+        let background = commands.spawn((
+            PbrBundle {
+                mesh: quad.clone(),
+                material: materials.add(StandardMaterial {
+                    base_color: Color::rgb(0.15, 0.15, 0.15),
+                    unlit: true,
+                    ..default()
+                }),
+                ..default()
+            },
+            Pickable::IGNORE,
+            HealthBar,
+        )).id();
+        let fill = commands.spawn((
+            PbrBundle {
+                mesh: quad,
+                material: materials.add(StandardMaterial {
+                    base_color: Color::GREEN,
+                    unlit: true,
+                    ..default()
+                }),
+                ..default()
+            },
+            Pickable::IGNORE,
+            HealthBarFill,
+        )).id();
+        commands.entity(entity).push_children(&[background, fill]);
+    }
+}
+
+fn health_color(fraction: f32) -> Color {
+    Color::rgb(1. - fraction, fraction, 0.)
+}
+
+#[allow(clippy::type_complexity)]
+fn update_health_bars(
+    roles: Res<roles::Roles>,
+    changed: Query<(), Or<(Changed<TokenHealth>, Changed<tokens::StrippedTokenData>, Changed<Children>)>>,
+    tokens: Query<(&TokenHealth, &tokens::StrippedTokenData, &Children)>,
+    mut backgrounds: Query<(&mut Transform, &mut Visibility), (With<HealthBar>, Without<HealthBarFill>)>,
+    mut fills: Query<(&mut Transform, &mut Visibility, &Handle<StandardMaterial>), (With<HealthBarFill>, Without<HealthBar>)>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    if !roles.is_changed() && changed.is_empty() {
+        return;
+    }
+    for (health, data, children) in tokens.iter() {
+        let radius = data.get_radius();
+        let width = radius * 2.;
+        let fraction = if data.hit_points > 0 {
+            (health.current as f32 / data.hit_points as f32).clamp(0., 1.)
+        } else {
+            0.
+        };
+        let visibility = if health.hidden && !roles.is_game_master() {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        };
+        //Local +y is up on the screen and +z is towards the camera
+        let position = Vec3::new(0., radius + BAR_GAP + BAR_HEIGHT / 2., 0.1);
+        for child in children.iter() {
+            if let Ok((mut transform, mut vis)) = backgrounds.get_mut(*child) {
+                *transform = Transform::from_translation(position)
+                    .with_scale(Vec3::new(width, BAR_HEIGHT, 1.));
+                *vis = visibility;
+            }
+            if let Ok((mut transform, mut vis, material)) = fills.get_mut(*child) {
+                //Shrinks towards the left edge of the bar
+                *transform = Transform::from_translation(position + Vec3::new(-width * (1. - fraction) / 2., 0., 0.01))
+                    .with_scale(Vec3::new((width * fraction).max(0.001), BAR_HEIGHT, 1.));
+                *vis = visibility;
+                if let Some(mat) = materials.get_mut(material) {
+                    mat.base_color = if health.temp > 0 { Color::CYAN } else { health_color(fraction) };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(current: i64, temp: i64) -> TokenHealth {
+        TokenHealth {
+            current,
+            temp,
+            hidden: false,
+        }
+    }
+
+    #[test]
+    fn damage_takes_temp_first() {
+        let mut hp = health(10, 5);
+        hp.damage(3);
+        assert_eq!((hp.current, hp.temp), (10, 2));
+        hp.damage(4);
+        assert_eq!((hp.current, hp.temp), (8, 0));
+    }
+
+    #[test]
+    fn damage_stops_at_zero() {
+        let mut hp = health(4, 0);
+        hp.damage(10);
+        assert_eq!(hp.current, 0);
+    }
+
+    #[test]
+    fn heal_stops_at_max() {
+        let mut hp = health(4, 0);
+        hp.heal(3, 10);
+        assert_eq!(hp.current, 7);
+        hp.heal(30, 10);
+        assert_eq!(hp.current, 10);
+    }
+
+    #[test]
+    fn temp_keeps_the_higher_amount() {
+        let mut hp = health(10, 5);
+        hp.add_temp(3);
+        assert_eq!(hp.temp, 5);
+        hp.add_temp(8);
+        assert_eq!(hp.temp, 8);
+    }
+}
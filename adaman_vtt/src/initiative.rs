@@ -0,0 +1,216 @@
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::maps;
+use crate::networking;
+use crate::orders;
+use crate::tokens;
+
+pub struct InitiativePlugin;
+
+impl Plugin for InitiativePlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<Initiative>()
+            .add_systems(Update, highlight_active_token)
+        ;
+    }
+}
+
+//Turn order for the current combat, round 0 is before combat has started
+#[derive(Resource, Serialize, Deserialize, Clone, Default)]
+pub struct Initiative {
+    pub entries: Vec<InitiativeEntry>,
+    pub turn: usize,
+    pub round: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct InitiativeEntry {
+    pub id: tokens::TokenId,
+    pub roll: i64,
+    //Breaks ties, higher dexterity goes first
+    pub dexterity: i64,
+}
+
+impl Initiative {
+    pub fn active(&self) -> Option<tokens::TokenId> {
+        if self.round == 0 {
+            return None;
+        }
+        self.entries.get(self.turn).map(|entry| entry.id)
+    }
+
+    //Rolling again replaces a token's old entry
+    pub fn add(&mut self, entry: InitiativeEntry) {
+        self.remove(entry.id);
+        self.entries.push(entry);
+        self.sort();
+    }
+
+    pub fn remove(&mut self, id: tokens::TokenId) {
+        let active = self.active();
+        self.entries.retain(|entry| entry.id != id);
+        self.restore_turn(active);
+    }
+
+    //Highest first, keeping whoever's turn it is on their turn
+    pub fn sort(&mut self) {
+        let active = self.active();
+        self.entries.sort_by(|a, b| b.roll.cmp(&a.roll).then(b.dexterity.cmp(&a.dexterity)));
+        self.restore_turn(active);
+    }
+
+    fn restore_turn(&mut self, active: Option<tokens::TokenId>) {
+        if let Some(turn) = active.and_then(|id| self.entries.iter().position(|entry| entry.id == id)) {
+            self.turn = turn;
+        }
+        if self.turn >= self.entries.len() {
+            self.turn = 0;
+        }
+    }
+
+    pub fn next_turn(&mut self) {
+        if self.entries.is_empty() {
+            return;
+        }
+        if self.round == 0 {
+            self.round = 1;
+            self.turn = 0;
+            return;
+        }
+        self.turn += 1;
+        if self.turn >= self.entries.len() {
+            self.turn = 0;
+            self.round += 1;
+        }
+    }
+
+    pub fn previous_turn(&mut self) {
+        if self.entries.is_empty() || self.round == 0 {
+            return;
+        }
+        if self.turn > 0 {
+            self.turn -= 1;
+        } else if self.round > 1 {
+            self.turn = self.entries.len() - 1;
+            self.round -= 1;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Initiative::default();
+    }
+}
+
+//None if the dice couldn't be rolled, rather than putting the token in with a made up number
+pub fn roll_initiative(id: tokens::TokenId, dexterity: i64) -> Option<InitiativeEntry> {
+    let d20 = cute_dnd_dice::Roll::from_str("1d20").ok()?.roll() as i64;
+    Some(InitiativeEntry {
+        id,
+        roll: d20 + tokens::TokenStats::modifier(dexterity),
+        dexterity,
+    })
+}
+
+//The whole turn order is sent every time so peers can't drift apart
+pub fn send_initiative(
+    initiative: Initiative,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    ev_client.send(networking::ClientCommandEvent {
+        order: orders::OrderEvent {
+            command: orders::Command::SetInitiative(orders::SetInitiativeCommand {
+                initiative,
+            }),
+        },
+        reliability: networking::NetworkReliability::Reliable,
+    });
+}
+
+fn highlight_active_token(
+    initiative: Res<Initiative>,
+    tokens: Query<(&tokens::TokenId, &GlobalTransform, &ViewVisibility, Option<&tokens::StrippedTokenData>)>,
+    mut gizmos: Gizmos,
+) {
+    let Some(active) = initiative.active() else {
+        return;
+    };
+    for (id, transform, visibility, data) in tokens.iter() {
+        //Tokens out of sight stay hidden
+        if *id != active || !visibility.get() {
+            continue;
+        }
+        let radius = data.map(|data| data.get_radius()).unwrap_or(maps::CELL_SIZE / 2.);
+        gizmos.circle(transform.translation(), Vec3::Y, radius + 0.5, Color::YELLOW);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(n: u128) -> tokens::TokenId {
+        tokens::TokenId(uuid::Uuid::from_u128(n))
+    }
+
+    fn entry(n: u128, roll: i64, dexterity: i64) -> InitiativeEntry {
+        InitiativeEntry {
+            id: token(n),
+            roll,
+            dexterity,
+        }
+    }
+
+    fn order(initiative: &Initiative) -> Vec<tokens::TokenId> {
+        initiative.entries.iter().map(|entry| entry.id).collect()
+    }
+
+    #[test]
+    fn highest_roll_then_dexterity_goes_first() {
+        let mut initiative = Initiative::default();
+        initiative.add(entry(1, 12, 10));
+        initiative.add(entry(2, 18, 10));
+        initiative.add(entry(3, 12, 16));
+        assert_eq!(order(&initiative), vec![token(2), token(3), token(1)]);
+    }
+
+    #[test]
+    fn turns_wrap_into_the_next_round() {
+        let mut initiative = Initiative::default();
+        initiative.add(entry(1, 15, 10));
+        initiative.add(entry(2, 5, 10));
+        assert_eq!(initiative.active(), None);
+        initiative.next_turn();
+        assert_eq!((initiative.round, initiative.active()), (1, Some(token(1))));
+        initiative.next_turn();
+        initiative.next_turn();
+        assert_eq!((initiative.round, initiative.active()), (2, Some(token(1))));
+        initiative.previous_turn();
+        assert_eq!((initiative.round, initiative.active()), (1, Some(token(2))));
+    }
+
+    #[test]
+    fn changes_keep_the_active_token() {
+        let mut initiative = Initiative::default();
+        initiative.add(entry(1, 15, 10));
+        initiative.add(entry(2, 10, 10));
+        initiative.next_turn();
+        initiative.next_turn();
+        initiative.add(entry(3, 20, 10));
+        assert_eq!(initiative.active(), Some(token(2)));
+        initiative.remove(token(1));
+        assert_eq!(initiative.active(), Some(token(2)));
+    }
+
+    #[test]
+    fn removing_the_active_token_moves_on() {
+        let mut initiative = Initiative::default();
+        initiative.add(entry(1, 15, 10));
+        initiative.add(entry(2, 10, 10));
+        initiative.next_turn();
+        initiative.remove(token(1));
+        assert_eq!(initiative.active(), Some(token(2)));
+        initiative.remove(token(2));
+        assert_eq!(initiative.active(), None);
+    }
+}
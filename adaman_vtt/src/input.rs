@@ -5,6 +5,8 @@ use bevy_mod_picking::prelude::*;
 use cute_dnd_dice;
 
 use rfd::AsyncFileDialog;
+use image::io::Reader as ImageReader;
+use std::io::Cursor;
 
 use crate::maps;
 use crate::tokens;
@@ -15,18 +17,46 @@ use crate::dd2vtt;
 use crate::fileload;
 use crate::files;
 use crate::encounters;
+use crate::roles;
+use crate::doors;
+use crate::ui;
 
 pub struct InputPlugin;
 
 impl Plugin for InputPlugin {
     fn build(&self, app: &mut App) {
         app.add_event::<TokenDragEvent>()
+            .add_event::<TokenDragEndEvent>()
+            .insert_resource(DragState{
+                sequence: 0,
+                last: std::collections::HashMap::new(),
+            })
             .add_systems(Update, poll_for_map)
             .add_event::<CreateMapFromFile>()
+            .add_event::<CalibratedMap>()
+            .add_systems(Update, create_calibrated_map)
             .add_systems(
                 Update,
                 recieve_dragging_tokens.before(orders::recieve_orders),
             )
+            .add_systems(
+                Update,
+                recieve_drag_end.after(recieve_dragging_tokens).before(orders::recieve_orders),
+            )
+            .add_event::<DoorClickEvent>()
+            .add_systems(Update, recieve_door_clicks.before(orders::recieve_orders))
+            .add_event::<TokenClickEvent>()
+            .init_resource::<TokenMenu>()
+            .add_systems(Update, recieve_token_clicks)
+            .init_resource::<PortraitFetches>()
+            .add_systems(Update, fetch_portraits)
+            .add_event::<UploadTokenPortrait>()
+            .add_systems(Update, poll_for_portrait)
+            .init_resource::<TokenEditor>()
+            .add_event::<OpenTokenEditor>()
+            .add_systems(Update, open_token_editor)
+            .add_event::<SaveTokenData>()
+            .add_systems(Update, save_token_data)
             .add_event::<CreateTokenFromData>()
             .add_systems(Update, create_token_from_data)
         ;
@@ -44,23 +74,115 @@ impl From<ListenerInput<Pointer<Drag>>> for TokenDragEvent {
     }
 }
 
+#[derive(Event)]
+pub struct TokenDragEndEvent {
+    pub input: ListenerInput<Pointer<DragEnd>>,
+}
+
+impl From<ListenerInput<Pointer<DragEnd>>> for TokenDragEndEvent {
+    fn from(input: ListenerInput<Pointer<DragEnd>>) -> TokenDragEndEvent {
+        TokenDragEndEvent { input }
+    }
+}
+
+#[derive(Event)]
+pub struct DoorClickEvent {
+    pub input: ListenerInput<Pointer<Click>>,
+}
+
+impl From<ListenerInput<Pointer<Click>>> for DoorClickEvent {
+    fn from(input: ListenerInput<Pointer<Click>>) -> DoorClickEvent {
+        DoorClickEvent { input }
+    }
+}
+
+#[derive(Event)]
+pub struct TokenClickEvent {
+    pub input: ListenerInput<Pointer<Click>>,
+}
+
+impl From<ListenerInput<Pointer<Click>>> for TokenClickEvent {
+    fn from(input: ListenerInput<Pointer<Click>>) -> TokenClickEvent {
+        TokenClickEvent { input }
+    }
+}
+
+//The token the GM right clicked on and where on the screen to show its menu
+#[derive(Resource, Default)]
+pub struct TokenMenu {
+    pub token: Option<tokens::TokenId>,
+    pub position: Vec2,
+}
+
+fn recieve_token_clicks(
+    mut ev_click: EventReader<TokenClickEvent>,
+    tokens: Query<&tokens::TokenId>,
+    roles: Res<roles::Roles>,
+    mut menu: ResMut<TokenMenu>,
+) {
+    for click in ev_click.read() {
+        if click.input.button != PointerButton::Secondary || !roles.is_game_master() {
+            continue;
+        }
+        let Ok(id) = tokens.get(click.input.listener()) else {
+            continue;
+        };
+        menu.token = Some(*id);
+        menu.position = click.input.pointer_location.position;
+    }
+}
+
+//Tokens that haven't loaded yet snap like Medium ones
+const DEFAULT_TOKEN_RADIUS: f32 = maps::CELL_SIZE / 2.;
+
+//Where each token we're dragging was last previewed, committed when the drag ends
+#[derive(Resource)]
+pub struct DragState {
+    sequence: u32,
+    last: std::collections::HashMap<tokens::TokenId, Vec2>,
+}
+
+impl DragState {
+    fn next_sequence(&mut self) -> u32 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn recieve_dragging_tokens(
     mut ev_drag: EventReader<TokenDragEvent>,
     mut ev_client: EventWriter<networking::ClientCommandEvent>,
-    tokens: Query<(&tokens::TokenId, &Transform)>,
+    tokens: Query<(&tokens::TokenId, &tokens::TokenOwner, &tokens::TokenLock, Option<&tokens::StrippedTokenData>)>,
     // query to get camera transform
     camera_q: Query<(&Camera, &GlobalTransform)>,
+    maps: Query<(&maps::MapDimensions, &GlobalTransform)>,
+    keys: Res<Input<KeyCode>>,
+    roles: Res<roles::Roles>,
+    mut drag_state: ResMut<DragState>,
+    local_peer_id: Option<Res<networking::LocalPeerId>>,
 ) {
+    let Some(local_peer_id) = local_peer_id else {
+        return
+    };
     let (camera, camera_transform) = camera_q.single();
 
-    let mut dict = std::collections::HashMap::<tokens::TokenId, (f32, f32)>::new();
+    //Holding alt places tokens freely
+    let snapping = !keys.any_pressed([KeyCode::AltLeft, KeyCode::AltRight]);
+
+    let mut dict = std::collections::HashMap::<tokens::TokenId, (f32, f32, f32)>::new();
     for drag_ev in ev_drag.read() {
         if let Ok(token) = tokens.get(drag_ev.input.listener()) {
+            //Players can only move their own tokens, and nobody moves a locked one
+            if !roles.can_local(Some(token.1)) || token.2.0 {
+                continue;
+            }
             dict.insert(
                 *token.0,
                 (
                     drag_ev.input.pointer_location.position.x,
                     drag_ev.input.pointer_location.position.y,
+                    token.3.map(|data| data.get_radius()).unwrap_or(DEFAULT_TOKEN_RADIUS),
                 ),
             );
         }
@@ -74,21 +196,114 @@ fn recieve_dragging_tokens(
             Vec3::new(0., 0., 0.),
             Vec3::new(0., 1., 0.),
         ) {
+            let mut new_pos = Vec2::new(new_pos.x, new_pos.z);
+            if snapping {
+                if let Some((dimensions, transform)) = maps.iter().find(|(dimensions, transform)| dimensions.contains(transform, new_pos)) {
+                    new_pos = dimensions.snap(transform, new_pos, token.1 .2);
+                }
+            }
+            drag_state.last.insert(*token.0, new_pos);
+            //Previews can be lost or arrive out of order, the sequence lets peers drop the stale ones
+            let sequence = drag_state.next_sequence();
             ev_client.send(networking::ClientCommandEvent {
                 order: orders::OrderEvent {
-                    command: orders::Command::Move(orders::MoveCommand {
+                    command: orders::Command::DragPreview(orders::DragPreviewCommand {
                         id: *token.0,
                         x: new_pos.x,
-                        y: new_pos.z,
+                        y: new_pos.y,
+                        peer_id: local_peer_id.id,
+                        sequence,
                     }),
                 },
-                reliability: networking::NetworkReliability::Reliable,
+                reliability: networking::NetworkReliability::Unreliable,
             })
         }
     }
 }
 
-fn get_plane_intersection(ray: Ray, plane_origin: Vec3, plane_normal: Vec3) -> Option<Vec3> {
+fn recieve_drag_end(
+    mut ev_drag_end: EventReader<TokenDragEndEvent>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+    tokens: Query<&tokens::TokenId>,
+    mut drag_state: ResMut<DragState>,
+    local_peer_id: Option<Res<networking::LocalPeerId>>,
+) {
+    let Some(local_peer_id) = local_peer_id else {
+        return
+    };
+    for drag_ev in ev_drag_end.read() {
+        let Ok(id) = tokens.get(drag_ev.input.listener()) else {
+            continue;
+        };
+        let Some(position) = drag_state.last.remove(id) else {
+            continue;
+        };
+        let sequence = drag_state.next_sequence();
+        ev_client.send(networking::ClientCommandEvent {
+            order: orders::OrderEvent {
+                command: orders::Command::Move(orders::MoveCommand {
+                    id: *id,
+                    x: position.x,
+                    y: position.y,
+                    peer_id: local_peer_id.id,
+                    sequence,
+                }),
+            },
+            reliability: networking::NetworkReliability::Reliable,
+        })
+    }
+}
+
+fn recieve_door_clicks(
+    mut ev_click: EventReader<DoorClickEvent>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+    mut events: EventWriter<ui::InsertLog>,
+    roles: Res<roles::Roles>,
+    doors: Query<(&doors::DoorIndex, &Parent)>,
+    maps: Query<(&maps::MapId, &maps::MapDimensions, &GlobalTransform, &maps::MapDoors)>,
+    tokens: Query<(&tokens::TokenId, &Transform, &tokens::TokenOwner)>,
+) {
+    for click in ev_click.read() {
+        let Ok((index, parent)) = doors.get(click.input.listener()) else {
+            continue;
+        };
+        let Ok((map_id, dimensions, transform, states)) = maps.get(parent.get()) else {
+            continue;
+        };
+        let Some(closed) = states.0.get(index.0) else {
+            continue;
+        };
+        //Players need one of their own tokens next to the door
+        let token = if roles.is_game_master() {
+            None
+        } else {
+            let token = tokens.iter()
+                .filter(|(_, _, owner)| roles.is_local_owner(owner))
+                .find(|(_, token_transform, _)| {
+                    let position = Vec2::new(token_transform.translation.x, token_transform.translation.z);
+                    doors::in_reach(dimensions, transform, index.0, position)
+                });
+            let Some((id, _, _)) = token else {
+                events.send(ui::InsertLog::new("None of your tokens can reach that door".to_string()));
+                continue;
+            };
+            Some(*id)
+        };
+        ev_client.send(networking::ClientCommandEvent {
+            order: orders::OrderEvent {
+                command: orders::Command::ToggleDoor(orders::ToggleDoorCommand {
+                    map_id: *map_id,
+                    index: index.0,
+                    closed: !closed,
+                    token,
+                }),
+            },
+            reliability: networking::NetworkReliability::Reliable,
+        })
+    }
+}
+
+pub fn get_plane_intersection(ray: Ray, plane_origin: Vec3, plane_normal: Vec3) -> Option<Vec3> {
     let ray_dir = ray.direction;
     let dot = plane_normal.dot(ray_dir);
     if dot.abs() > 1e-6 {
@@ -118,13 +333,66 @@ pub struct CreateMapFromFile {
     pub name: String,
 }
 
+//An image map waiting for the user to line the grid up with it
+#[derive(Resource)]
+pub struct MapCalibration {
+    pub name: String,
+    pub file: Vec<u8>,
+    pub image: Handle<Image>,
+    pub width: u32,
+    pub height: u32,
+    pub pixels_per: f64,
+    pub offset_x: f64,
+    pub offset_y: f64,
+    //Where the user started dragging over a cell, in image pixels
+    pub drag_start: Option<Vec2>,
+}
+
+impl MapCalibration {
+    pub fn grid(&self) -> maps::MapGrid {
+        maps::MapGrid {
+            pixels_per: self.pixels_per,
+            width: (self.width as f64 / self.pixels_per).ceil() as i64,
+            height: (self.height as f64 / self.pixels_per).ceil() as i64,
+            offset_x: self.offset_x,
+            offset_y: self.offset_y,
+            ..default()
+        }
+    }
+
+    //Use a rectangle dragged over one cell to work out the grid
+    pub fn set_cell(&mut self, start: Vec2, end: Vec2) {
+        let size = (end - start).abs();
+        //Cells are square, so split the difference if the drag wasn't
+        let pixels_per = ((size.x + size.y) / 2.) as f64;
+        if pixels_per < 1. {
+            return;
+        }
+        let corner = start.min(end);
+        self.pixels_per = pixels_per;
+        self.offset_x = (corner.x as f64).rem_euclid(pixels_per);
+        self.offset_y = (corner.y as f64).rem_euclid(pixels_per);
+    }
+}
+
+#[derive(Event)]
+pub struct CalibratedMap {
+    pub name: String,
+    pub data: maps::MapData,
+}
+
+//Guess for the grid before the user calibrates, most battlemaps are exported at 70px or so
+const DEFAULT_PIXELS_PER: f64 = 70.;
+
 //Poll to see if the user has selected a path
 pub fn poll_for_map(
+    mut commands: Commands,
     mut ev_client: EventWriter<networking::ClientCommandEvent>,
     mut bank: Option<ResMut<bank::Bank>>,
     mut register_event: EventWriter<files::RegisterMap>,
     mut poll_maps: AsyncTaskRunner<MapFile>,
     mut ev_load_map: EventReader<CreateMapFromFile>,
+    mut images: ResMut<Assets<Image>>,
 ) {
     //Make sure the bank is ready
     let Some(ref mut bank) = bank else {
@@ -141,8 +409,9 @@ pub fn poll_for_map(
             if let Some(ev) = load_event {
                 let task = async move{
                     let handle = AsyncFileDialog::new()
-                        //.add_filter("image", &["png", "jpg"]) TODO Add img maps
+                        .add_filter("map", &["dd2vtt", "json", "png", "jpg", "jpeg", "webp"])
                         .add_filter("universalVTT", &["dd2vtt", "json"])
+                        .add_filter("image", &["png", "jpg", "jpeg", "webp"])
                         .pick_file().await;
                     let Some(handle) = handle else {
                         return MapFile{
@@ -155,7 +424,6 @@ pub fn poll_for_map(
                         name: ev.name.clone(),
                     }
                 };
-                println!("Started Map Loading");
                 poll_maps.start(task);
             }
         },
@@ -167,38 +435,84 @@ pub fn poll_for_map(
                 return;
             };
 
-            let contents = String::from_utf8(contents).unwrap();
-            //println!("{:?}", contents);
-
             //Deserialize it into the RawMapData
-            let mut data: Option<maps::MapData> = None;
-            if let Ok(deserialized) = serde_json::from_str::<dd2vtt::DD2VTT>(contents.as_str()) {
-                data = Some(deserialized.into());
+            if let Ok(deserialized) = serde_json::from_slice::<dd2vtt::DD2VTT>(contents.as_slice()) {
+                import_map(
+                    deserialized.into(),
+                    file.name.to_string(),
+                    bank,
+                    &mut register_event,
+                    &mut ev_client,
+                );
+                return;
             };
 
-            //Make sure we found some data
-            let Some(data) = data else {
+            //Otherwise see if it's a plain image, which needs its grid calibrated first
+            let image_data = ImageReader::new(Cursor::new(contents.as_slice()))
+                .with_guessed_format()
+                .ok()
+                .and_then(|reader| reader.decode().ok());
+            let Some(image_data) = image_data else {
+                println!("Unrecognized map file");
                 return;
             };
-
-            //Insert the file data into the bank
-            let data = serde_json::to_vec(&data).ok().unwrap();
-            let load_identifier = bank.store(data.into());
-
-            register_event.send(
-                files::RegisterMap{
-                    load_identifier: load_identifier.clone(),
-                    name: file.name.to_string(),
-                }
-            );
-            create_map(
-                load_identifier.clone(),
-                &mut ev_client,
-            );
+            let width = image_data.width();
+            let height = image_data.height();
+            let image = images.add(Image::from_dynamic(image_data, true));
+            commands.insert_resource(MapCalibration {
+                name: file.name.to_string(),
+                file: contents,
+                image,
+                width,
+                height,
+                pixels_per: DEFAULT_PIXELS_PER,
+                offset_x: 0.,
+                offset_y: 0.,
+                drag_start: None,
+            });
         }
     }
 }
 
+pub fn create_calibrated_map(
+    mut ev_calibrated: EventReader<CalibratedMap>,
+    mut bank: ResMut<bank::Bank>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+    mut register_event: EventWriter<files::RegisterMap>,
+) {
+    for ev in ev_calibrated.read() {
+        import_map(
+            ev.data.clone(),
+            ev.name.clone(),
+            &mut bank,
+            &mut register_event,
+            &mut ev_client,
+        );
+    }
+}
+
+fn import_map(
+    data: maps::MapData,
+    name: String,
+    bank: &mut bank::Bank,
+    register_event: &mut EventWriter<files::RegisterMap>,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    //Insert the file data into the bank
+    let data = serde_json::to_vec(&data).ok().unwrap();
+    let load_identifier = bank.store(data.into());
+
+    register_event.send(
+        files::RegisterMap{
+            load_identifier: load_identifier.clone(),
+            name,
+        }
+    );
+    create_map(
+        load_identifier.clone(),
+        ev_client,
+    );
+}
 
 pub fn create_map(
     load_identifier: fileload::LoadIdentifier,
@@ -212,6 +526,8 @@ pub fn create_map(
                 y: 0.,
                 data_id: load_identifier.clone(),
                 map_id: maps::get_new_id(),
+                doors: Vec::new(),
+                fog: None,
             }),
         },
         reliability: networking::NetworkReliability::Reliable,
@@ -238,28 +554,262 @@ pub struct CreateTokenFromData{
     pub data: tokens::TokenData,
 }
 
+//Tokens waiting on their portrait to be downloaded before they're stored
+#[derive(Resource, Default)]
+pub struct PortraitFetches {
+    queue: std::collections::VecDeque<tokens::TokenData>,
+}
+
 pub fn create_token_from_data(
     mut ev_create: EventReader<CreateTokenFromData>,
     mut bank: ResMut<bank::Bank>,
     mut ev_client: EventWriter<networking::ClientCommandEvent>,
     mut register_event: EventWriter<files::RegisterToken>,
+    mut fetches: ResMut<PortraitFetches>,
 ) {
     for ev in ev_create.read() {
-        let data = &ev.data;
-        //Insert the file data into the bank
-        let data_serialized = serde_json::to_vec(data).ok().unwrap();
-        let load_identifier = bank.store(data_serialized.into());
+        //Fetch the portrait once here so peers get it with the token instead of all going to the url
+        if !ev.data.has_image() && ev.data.img.is_some() {
+            fetches.queue.push_back(ev.data.clone());
+            continue;
+        }
+        store_token(&ev.data, &mut bank, &mut register_event, &mut ev_client);
+    }
+}
 
-        register_event.send(
-            files::RegisterToken{
-                load_identifier: load_identifier.clone(),
-                name: data.name.to_string(),
+pub fn fetch_portraits(
+    mut fetches: ResMut<PortraitFetches>,
+    mut poll_fetch: AsyncTaskRunner<(tokens::TokenData, Option<Vec<u8>>)>,
+    mut bank: ResMut<bank::Bank>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+    mut register_event: EventWriter<files::RegisterToken>,
+) {
+    match poll_fetch.poll() {
+        AsyncTaskStatus::Idle => {
+            let Some(data) = fetches.queue.pop_front() else {
+                return;
+            };
+            let Some(url) = data.img.clone() else {
+                return;
+            };
+            let task = async move {
+                let image = match reqwest::get(url).await {
+                    Ok(response) => response.bytes().await.ok().map(|bytes| bytes.to_vec()),
+                    Err(_) => None,
+                };
+                (data, image)
+            };
+            poll_fetch.start(task);
+        },
+        AsyncTaskStatus::Pending => {},
+        AsyncTaskStatus::Finished((mut data, image)) => {
+            //Still make the token if the portrait couldn't be had
+            if !image.is_some_and(|image| data.set_image(&image)) {
+                println!("Unable to fetch portrait for {}", data.name);
             }
-        );
-        create_token(
-            load_identifier.clone(),
-            &mut ev_client,
-        );
+            store_token(&data, &mut bank, &mut register_event, &mut ev_client);
+        },
+    }
+}
+
+fn store_token(
+    data: &tokens::TokenData,
+    bank: &mut bank::Bank,
+    register_event: &mut EventWriter<files::RegisterToken>,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    //Insert the file data into the bank
+    let data_serialized = serde_json::to_vec(data).ok().unwrap();
+    let load_identifier = bank.store(data_serialized.into());
+
+    register_event.send(
+        files::RegisterToken{
+            load_identifier: load_identifier.clone(),
+            name: data.name.to_string(),
+        }
+    );
+    create_token(
+        load_identifier.clone(),
+        ev_client,
+    );
+}
+
+//Have the user pick a portrait for a token
+#[derive(Event, Clone)]
+pub struct UploadTokenPortrait {
+    pub target: PortraitTarget,
+}
+
+#[derive(Clone)]
+pub enum PortraitTarget {
+    //Replace the portrait of a token in the library
+    Library(fileload::LoadIdentifier),
+    //The token open in the editor
+    Editor,
+}
+
+pub struct PortraitFile {
+    file: Option<Vec<u8>>,
+    target: PortraitTarget,
+}
+
+pub fn poll_for_portrait(
+    mut ev_upload: EventReader<UploadTokenPortrait>,
+    mut poll_portrait: AsyncTaskRunner<PortraitFile>,
+    mut bank: ResMut<bank::Bank>,
+    mut replace_event: EventWriter<files::ReplaceToken>,
+    mut editor: ResMut<TokenEditor>,
+) {
+    let mut upload_event: Option<UploadTokenPortrait> = None;
+    for ev in ev_upload.read() {
+        upload_event = Some(ev.clone());
+    }
+
+    match poll_portrait.poll() {
+        AsyncTaskStatus::Idle => {
+            if let Some(ev) = upload_event {
+                let task = async move {
+                    let handle = AsyncFileDialog::new()
+                        .add_filter("image", &["png", "jpg", "jpeg", "webp"])
+                        .pick_file().await;
+                    PortraitFile {
+                        file: match handle {
+                            Some(handle) => Some(handle.read().await),
+                            None => None,
+                        },
+                        target: ev.target,
+                    }
+                };
+                poll_portrait.start(task);
+            }
+        },
+        AsyncTaskStatus::Pending => {},
+        AsyncTaskStatus::Finished(file) => {
+            let Some(contents) = file.file else {
+                return;
+            };
+            let PortraitTarget::Library(old) = file.target else {
+                if !editor.data.set_image(&contents) {
+                    println!("Not a usable portrait image");
+                }
+                return;
+            };
+            let Some(mut data) = bank.request_data(&old.data_id)
+                .and_then(|data| serde_json::from_slice::<tokens::TokenData>(data.as_slice()).ok()) else {
+                println!("Token to add a portrait to is missing");
+                return;
+            };
+            if !data.set_image(&contents) {
+                println!("Not a usable portrait image");
+                return;
+            }
+            //Stored as new data so tokens already placed keep the old one
+            let data_serialized = serde_json::to_vec(&data).ok().unwrap();
+            let load_identifier = bank.store(data_serialized.into());
+            replace_event.send(files::ReplaceToken{
+                old,
+                name: data.name.clone(),
+                load_identifier,
+            });
+        },
+    }
+}
+
+//The token being written up by hand, and the library entry it came from if it's being edited
+#[derive(Resource, Default)]
+pub struct TokenEditor {
+    pub open: bool,
+    pub data: tokens::TokenData,
+    pub editing: Option<fileload::LoadIdentifier>,
+    //Set when editing a token on the board rather than in the library
+    pub board_token: Option<tokens::TokenId>,
+}
+
+#[derive(Event)]
+pub struct OpenTokenEditor {
+    //None for a brand new token
+    pub load_identifier: Option<fileload::LoadIdentifier>,
+    pub board_token: Option<tokens::TokenId>,
+}
+
+pub fn open_token_editor(
+    mut ev_open: EventReader<OpenTokenEditor>,
+    mut editor: ResMut<TokenEditor>,
+    bank: Res<bank::Bank>,
+) {
+    for ev in ev_open.read() {
+        let data = match &ev.load_identifier {
+            Some(load_identifier) => {
+                let Some(data) = bank.request_data(&load_identifier.data_id)
+                    .and_then(|data| serde_json::from_slice::<tokens::TokenData>(data.as_slice()).ok()) else {
+                    println!("Token to edit is missing");
+                    continue;
+                };
+                data
+            },
+            None => tokens::TokenData{
+                format: TOKEN_FORMAT,
+                name: "New Token".to_string(),
+                size: "Medium".to_string(),
+                hit_points: 10,
+                armor_class: 10,
+                ..default()
+            },
+        };
+        *editor = TokenEditor {
+            open: true,
+            data,
+            editing: ev.load_identifier.clone(),
+            board_token: ev.board_token,
+        };
+    }
+}
+
+//Same version as tokens from Open5e
+const TOKEN_FORMAT: f32 = 0.1;
+
+#[derive(Event)]
+pub struct SaveTokenData;
+
+pub fn save_token_data(
+    mut ev_save: EventReader<SaveTokenData>,
+    mut editor: ResMut<TokenEditor>,
+    mut bank: ResMut<bank::Bank>,
+    mut register_event: EventWriter<files::RegisterToken>,
+    mut replace_event: EventWriter<files::ReplaceToken>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+) {
+    for _ev in ev_save.read() {
+        let data_serialized = serde_json::to_vec(&editor.data).ok().unwrap();
+        //Stored as new data so tokens already placed keep the old version
+        let load_identifier = bank.store(data_serialized.into());
+        //A token on the board only changes that one token, the library is left alone
+        if let Some(id) = editor.board_token.take() {
+            editor.editing = None;
+            editor.open = false;
+            ev_client.send(networking::ClientCommandEvent {
+                order: orders::OrderEvent {
+                    command: orders::Command::ChangeTokenData(orders::ChangeTokenDataCommand {
+                        id,
+                        load_identifier,
+                    }),
+                },
+                reliability: networking::NetworkReliability::Reliable,
+            });
+            continue;
+        }
+        match editor.editing.take() {
+            Some(old) => replace_event.send(files::ReplaceToken{
+                old,
+                name: editor.data.name.clone(),
+                load_identifier,
+            }),
+            None => register_event.send(files::RegisterToken{
+                name: editor.data.name.clone(),
+                load_identifier,
+            }),
+        }
+        editor.open = false;
     }
 }
 
@@ -274,6 +824,85 @@ pub fn create_token(
                 y: 0.,
                 id: tokens::get_new_id(),
                 load_identifier, 
+                owned_by: None,
+                locked: false,
+                health: None,
+                conditions: Default::default(),
+            }),
+        },
+        reliability: networking::NetworkReliability::Reliable,
+    });
+}
+
+pub fn delete_token(
+    id: tokens::TokenId,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    ev_client.send(networking::ClientCommandEvent {
+        order: orders::OrderEvent {
+            command: orders::Command::DeleteToken(orders::DeleteTokenCommand {
+                id,
+            }),
+        },
+        reliability: networking::NetworkReliability::Reliable,
+    });
+}
+
+pub fn duplicate_token(
+    id: tokens::TokenId,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    ev_client.send(networking::ClientCommandEvent {
+        order: orders::OrderEvent {
+            command: orders::Command::DuplicateToken(orders::DuplicateTokenCommand {
+                id,
+                new_id: tokens::get_new_id(),
+            }),
+        },
+        reliability: networking::NetworkReliability::Reliable,
+    });
+}
+
+pub fn lock_token(
+    id: tokens::TokenId,
+    locked: bool,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    ev_client.send(networking::ClientCommandEvent {
+        order: orders::OrderEvent {
+            command: orders::Command::LockToken(orders::LockTokenCommand {
+                id,
+                locked,
+            }),
+        },
+        reliability: networking::NetworkReliability::Reliable,
+    });
+}
+
+pub fn set_lighting(
+    dynamic: bool,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    ev_client.send(networking::ClientCommandEvent {
+        order: orders::OrderEvent {
+            command: orders::Command::SetLighting(orders::SetLightingCommand {
+                dynamic,
+            }),
+        },
+        reliability: networking::NetworkReliability::Reliable,
+    });
+}
+
+pub fn assign_owner(
+    id: tokens::TokenId,
+    owner: Option<tokens::Owner>,
+    ev_client: &mut EventWriter<networking::ClientCommandEvent>,
+) {
+    ev_client.send(networking::ClientCommandEvent {
+        order: orders::OrderEvent {
+            command: orders::Command::AssignOwner(orders::AssignOwnerCommand {
+                id,
+                owner,
             }),
         },
         reliability: networking::NetworkReliability::Reliable,
@@ -291,6 +920,7 @@ pub fn save_encounter(
 
 pub fn send_message(
     text: String,
+    name: String,
     ev_client: &mut EventWriter<networking::ClientCommandEvent>,
     local_peer_id: Res<networking::LocalPeerId>,
 ) {
@@ -310,6 +940,7 @@ pub fn send_message(
                     crate::ui::RecieveMessage{
                         text: text.clone(),
                         from: local_peer_id.id,
+                        name,
                         roll,
                     }
                 )
@@ -0,0 +1,136 @@
+use bevy::prelude::*;
+use bevy_egui::{egui, EguiContexts};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::bank;
+use crate::files;
+use crate::filetransfer;
+use crate::networking;
+
+pub struct JoinPlugin;
+
+impl Plugin for JoinPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, load_join_settings)
+            .add_systems(Update, join_screen)
+        ;
+    }
+}
+
+const DEFAULT_SERVER_URL: &str = "wss://matchbox-server-woj7mv63ka-uc.a.run.app";
+const DEFAULT_ROOM: &str = "adamantvtt";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct JoinSettings {
+    pub server_url: String,
+    pub room: String,
+    pub name: String,
+    #[serde(default)]
+    pub game_master: bool,
+    //How many files to download at once, more is faster on a good connection
+    #[serde(default = "default_download_window")]
+    pub download_window: usize,
+}
+
+fn default_download_window() -> usize {
+    filetransfer::DOWNLOAD_WINDOW
+}
+
+impl Default for JoinSettings {
+    fn default() -> Self {
+        JoinSettings {
+            server_url: DEFAULT_SERVER_URL.to_string(),
+            room: DEFAULT_ROOM.to_string(),
+            name: "Player".to_string(),
+            game_master: false,
+            download_window: default_download_window(),
+        }
+    }
+}
+
+impl JoinSettings {
+    pub fn room_url(&self) -> String {
+        format!("{}/{}", self.server_url.trim().trim_end_matches('/'), self.room.trim())
+    }
+
+    fn is_valid(&self) -> bool {
+        let url = self.server_url.trim();
+        (url.starts_with("ws://") || url.starts_with("wss://"))
+            && !self.room.trim().is_empty()
+            && !self.name.trim().is_empty()
+    }
+}
+
+//The settings the user is editing on the join screen, and whether they have joined yet
+#[derive(Resource)]
+pub struct JoinScreen {
+    pub settings: JoinSettings,
+    pub joined: bool,
+}
+
+impl bank::Bank {
+    pub fn get_join_settings(&self) -> Option<JoinSettings> {
+        serde_json::from_slice(self.request_data(&files::JOIN_SETTINGS_ID)?.as_slice()).ok()
+    }
+
+    pub fn set_join_settings(&mut self, settings: &JoinSettings) {
+        let data = Arc::new(serde_json::to_vec(settings).ok().unwrap());
+        self.store_at_id(&files::JOIN_SETTINGS_ID, data);
+    }
+}
+
+fn load_join_settings(
+    mut commands: Commands,
+    bank: Res<bank::Bank>,
+) {
+    //Start with whatever was used last time
+    let settings = bank.get_join_settings().unwrap_or_default();
+    commands.insert_resource(JoinScreen{
+        settings,
+        joined: false,
+    });
+}
+
+fn join_screen(
+    mut contexts: EguiContexts,
+    mut join: ResMut<JoinScreen>,
+    mut bank: ResMut<bank::Bank>,
+    mut ev_join: EventWriter<networking::JoinRoom>,
+) {
+    if join.joined {
+        return;
+    }
+    egui::Window::new("Join Table")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Grid::new("join_grid").num_columns(2).show(ui, |ui| {
+                ui.label("Server");
+                ui.text_edit_singleline(&mut join.settings.server_url);
+                ui.end_row();
+                ui.label("Room");
+                ui.text_edit_singleline(&mut join.settings.room);
+                ui.end_row();
+                ui.label("Name");
+                ui.text_edit_singleline(&mut join.settings.name);
+                ui.end_row();
+                ui.label("Role");
+                ui.checkbox(&mut join.settings.game_master, "Game Master");
+                ui.end_row();
+                ui.label("Parallel Downloads");
+                ui.add(egui::DragValue::new(&mut join.settings.download_window).clamp_range(1..=16));
+                ui.end_row();
+            });
+            let valid = join.settings.is_valid();
+            let join_btn = ui.add_enabled(valid, egui::Button::new("Join"));
+            if join_btn.clicked() {
+                bank.set_join_settings(&join.settings);
+                ev_join.send(networking::JoinRoom{
+                    settings: join.settings.clone(),
+                });
+                join.joined = true;
+            }
+        });
+}
@@ -0,0 +1,108 @@
+use bevy::prelude::*;
+
+use crate::maps;
+
+pub struct LightingPlugin;
+
+impl Plugin for LightingPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Lighting{
+                dynamic: false,
+                ambient: Color::WHITE,
+                chosen: false,
+            })
+            .add_systems(Update, spawn_map_lights)
+            .add_systems(Update, apply_lighting.after(spawn_map_lights))
+        ;
+    }
+}
+
+//Baked shows the map image as drawn, dynamic lights it with the lights from the map file
+#[derive(Resource)]
+pub struct Lighting {
+    pub dynamic: bool,
+    pub ambient: Color,
+    //Set once the GM has picked, so loading another map doesn't undo it
+    pub chosen: bool,
+}
+
+//The light from startup, used whenever the map's own lights aren't
+#[derive(Component)]
+pub struct DefaultLight;
+
+#[derive(Component)]
+pub struct MapLightFlag;
+
+//How high the map's lights float over it
+const LIGHT_HEIGHT: f32 = 4.;
+//dd2vtt intensities are around 1, bevy wants lumens
+const LUMENS_PER_INTENSITY: f32 = 1500.;
+const AMBIENT_BRIGHTNESS: f32 = 0.2;
+
+fn color_from(rgba: [f32; 4]) -> Color {
+    let [r, g, b, a] = rgba;
+    Color::rgba(r, g, b, a)
+}
+
+fn spawn_map_lights(
+    mut commands: Commands,
+    maps: Query<(Entity, &maps::MapDimensions), Added<maps::MapDimensions>>,
+    mut lighting: ResMut<Lighting>,
+) {
+    for (entity, dimensions) in maps.iter() {
+        for light in dimensions.lights.iter() {
+            let position = dimensions.grid_to_local(light.position.x, light.position.y) + Vec3::Z * LIGHT_HEIGHT;
+            let map_light = commands.spawn((
+                PointLightBundle {
+                    point_light: PointLight {
+                        color: color_from(light.color),
+                        intensity: light.intensity as f32 * LUMENS_PER_INTENSITY,
+                        range: light.range as f32 * maps::CELL_SIZE,
+                        shadows_enabled: light.shadows,
+                        ..default()
+                    },
+                    transform: Transform::from_translation(position),
+                    visibility: Visibility::Hidden,
+                    ..default()
+                },
+                MapLightFlag,
+            )).id();
+            commands.entity(entity).push_children(&[map_light]);
+        }
+        //Start however the map was made to be seen, unless the GM has already picked
+        if !lighting.chosen {
+            lighting.dynamic = !dimensions.environment.baked_lighting;
+        }
+        lighting.ambient = color_from(dimensions.environment.ambient_light);
+    }
+}
+
+#[allow(clippy::type_complexity)]
+fn apply_lighting(
+    lighting: Res<Lighting>,
+    new_lights: Query<(), Added<MapLightFlag>>,
+    mut lights: Query<(&mut Visibility, Option<&DefaultLight>), Or<(With<MapLightFlag>, With<DefaultLight>)>>,
+    maps: Query<&Handle<StandardMaterial>, With<maps::MapLoaded>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut ambient: ResMut<AmbientLight>,
+) {
+    if !lighting.is_changed() && new_lights.is_empty() {
+        return;
+    }
+    for (mut visibility, default_light) in lights.iter_mut() {
+        let on = lighting.dynamic != default_light.is_some();
+        *visibility = if on { Visibility::Visible } else { Visibility::Hidden };
+    }
+    //Baked maps already have their lighting, so don't shade them again
+    for material in maps.iter() {
+        if let Some(mat) = materials.get_mut(material) {
+            mat.unlit = !lighting.dynamic;
+        }
+    }
+    if lighting.dynamic {
+        ambient.color = lighting.ambient.with_a(1.);
+        ambient.brightness = AMBIENT_BRIGHTNESS * lighting.ambient.a();
+    } else {
+        *ambient = AmbientLight::default();
+    }
+}
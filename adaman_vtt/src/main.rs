@@ -19,6 +19,16 @@ mod bank;
 mod fileload;
 mod files;
 mod encounters;
+mod join;
+mod roles;
+mod grid;
+mod vision;
+mod doors;
+mod lighting;
+mod fog;
+mod health;
+mod initiative;
+mod conditions;
 
 mod dd2vtt;
 mod open5e;
@@ -44,10 +54,20 @@ fn main() {
         .add_plugins(DefaultPickingPlugins.build().disable::<DebugPickingPlugin>())
         .add_plugins(ui::UIPlugin)
         .add_plugins(startup::GameStartPlugin)
+        .add_plugins(join::JoinPlugin)
         .add_plugins(networking::NetworkingPlugin)
+        .add_plugins(roles::RolesPlugin)
         .add_plugins(orders::OrdersPlugin)
         .add_plugins(maps::MapPlugin)
+        .add_plugins(grid::GridPlugin)
+        .add_plugins(vision::VisionPlugin)
+        .add_plugins(doors::DoorPlugin)
+        .add_plugins(lighting::LightingPlugin)
+        .add_plugins(fog::FogPlugin)
         .add_plugins(tokens::TokenPlugin)
+        .add_plugins(health::HealthPlugin)
+        .add_plugins(initiative::InitiativePlugin)
+        .add_plugins(conditions::ConditionsPlugin)
         .add_plugins(encounters::EncounterPlugin)
         .add_plugins(open5e::Open5ePlugin)
         .run();
@@ -22,6 +22,7 @@ impl Plugin for MapPlugin {
 pub struct MapBundle {
     pub id: MapId,
     pub load_identifier: fileload::LoadIdentifier,
+    pub doors: MapDoors,
     #[bundle()]
     pub pbr: PbrBundle,
 }
@@ -38,6 +39,7 @@ impl MapBundle {
         id: MapId,
         load_identifier: fileload::LoadIdentifier,
         position: Vec3,
+        doors: MapDoors,
         meshes: &mut ResMut<Assets<Mesh>>,
         materials: &mut ResMut<Assets<StandardMaterial>>,
     ) -> MapBundle {
@@ -61,10 +63,15 @@ impl MapBundle {
             },
             id,
             load_identifier,
+            doors,
         }
     }
 }
 
+//Whether each of the map's doors is closed, filled in from the map data once it loads
+#[derive(Component, Clone, Default)]
+pub struct MapDoors(pub Vec<bool>);
+
 #[derive(Event, Serialize, Deserialize, Clone)]
 pub struct MapLoad {
     pub map_id: MapId,
@@ -76,11 +83,35 @@ impl MapData {
         format: f64,
         image_str: String,
         grid: MapGrid,
+        walls: Vec<Wall>,
+        doors: Vec<Door>,
+        lights: Vec<MapLight>,
+        environment: MapEnvironment,
     ) -> MapData {
         MapData{
             format,
             image_str,
             grid,
+            walls,
+            doors,
+            lights,
+            environment,
+        }
+    }
+
+    //For maps that are just an image, the grid comes from calibrating it by hand
+    pub fn from_image(
+        image: &[u8],
+        grid: MapGrid,
+    ) -> MapData {
+        MapData{
+            format: 0.,
+            image_str: general_purpose::STANDARD.encode(image),
+            grid,
+            walls: Vec::new(),
+            doors: Vec::new(),
+            lights: Vec::new(),
+            environment: MapEnvironment::default(),
         }
     }
 
@@ -98,15 +129,246 @@ pub struct MapData {
     pub format: f64,
     image_str: String,
     pub grid: MapGrid,
+    #[serde(default)]
+    pub walls: Vec<Wall>,
+    #[serde(default)]
+    pub doors: Vec<Door>,
+    #[serde(default)]
+    pub lights: Vec<MapLight>,
+    #[serde(default)]
+    pub environment: MapEnvironment,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapLight {
+    pub position: GridPoint,
+    //In grid cells
+    pub range: f64,
+    pub intensity: f64,
+    pub color: [f32; 4],
+    pub shadows: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MapEnvironment {
+    //The lighting is already painted into the image
+    pub baked_lighting: bool,
+    pub ambient_light: [f32; 4],
+}
+
+impl Default for MapEnvironment {
+    fn default() -> Self {
+        MapEnvironment {
+            baked_lighting: true,
+            ambient_light: [1., 1., 1., 1.],
+        }
+    }
+}
+
+//A line of connected points that blocks sight, in grid coordinates
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Wall {
+    pub points: Vec<GridPoint>,
+}
+
+//A door across a gap in the walls, blocks sight while it's closed
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Door {
+    pub position: GridPoint,
+    pub bounds: Vec<GridPoint>,
+    pub closed: bool,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct GridPoint {
+    pub x: f64,
+    pub y: f64,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct MapGrid {
-    pub pixels_per: i64,
+    pub pixels_per: f64,
     pub width: i64,
     pub height: i64,
+    //Where the first grid line is in the image, in pixels
+    #[serde(default)]
+    pub offset_x: f64,
+    #[serde(default)]
+    pub offset_y: f64,
+    //Grid coordinate of the image's top left corner, dd2vtt's map_origin
+    #[serde(default)]
+    pub origin_x: f64,
+    #[serde(default)]
+    pub origin_y: f64,
+}
+
+impl MapGrid {
+    pub fn grid_to_pixel(&self, x: f64, y: f64) -> Vec2 {
+        Vec2::new(
+            ((x - self.origin_x) * self.pixels_per + self.offset_x) as f32,
+            ((y - self.origin_y) * self.pixels_per + self.offset_y) as f32,
+        )
+    }
+
+    pub fn pixel_to_grid(&self, px: f32, py: f32) -> (f64, f64) {
+        (
+            (px as f64 - self.offset_x) / self.pixels_per + self.origin_x,
+            (py as f64 - self.offset_y) / self.pixels_per + self.origin_y,
+        )
+    }
 }
 
+//The grid of a loaded map and how big its image turned out, for anything drawn on top of it
+#[derive(Component, Clone)]
+pub struct MapDimensions {
+    pub grid: MapGrid,
+    pub walls: Vec<Wall>,
+    pub doors: Vec<Door>,
+    pub lights: Vec<MapLight>,
+    pub environment: MapEnvironment,
+    pub pixel_width: u32,
+    pub pixel_height: u32,
+    pub size: Vec2,
+}
+
+impl MapDimensions {
+    //Position on the map quad, before the map's transform is applied
+    pub fn pixel_to_local(&self, pixel: Vec2) -> Vec3 {
+        Vec3::new(
+            (pixel.x / self.pixel_width as f32 - 0.5) * self.size.x,
+            (0.5 - pixel.y / self.pixel_height as f32) * self.size.y,
+            0.,
+        )
+    }
+
+    pub fn local_to_pixel(&self, local: Vec3) -> Vec2 {
+        Vec2::new(
+            (local.x / self.size.x + 0.5) * self.pixel_width as f32,
+            (0.5 - local.y / self.size.y) * self.pixel_height as f32,
+        )
+    }
+
+    //Size of the texture used to track fog and sight over this map
+    pub fn texels(&self) -> (u32, u32) {
+        (
+            (self.size.x / CELL_SIZE * TEXELS_PER_CELL).ceil().max(1.) as u32,
+            (self.size.y / CELL_SIZE * TEXELS_PER_CELL).ceil().max(1.) as u32,
+        )
+    }
+
+    //Where the middle of a texel is on the ground plane
+    pub fn texel_to_world(&self, transform: &GlobalTransform, x: u32, y: u32) -> Vec2 {
+        let (width, height) = self.texels();
+        let pixel = Vec2::new(
+            (x as f32 + 0.5) / width as f32 * self.pixel_width as f32,
+            (y as f32 + 0.5) / height as f32 * self.pixel_height as f32,
+        );
+        let world = transform.transform_point(self.pixel_to_local(pixel));
+        Vec2::new(world.x, world.z)
+    }
+
+    //Which texel a point on the ground plane falls in, if it's over the map at all
+    pub fn world_to_texel(&self, transform: &GlobalTransform, point: Vec2) -> Option<(u32, u32)> {
+        let texel = self.world_to_texel_space(transform, point);
+        let (width, height) = self.texels();
+        if texel.x < 0. || texel.y < 0. || texel.x >= width as f32 || texel.y >= height as f32 {
+            return None;
+        }
+        Some((texel.x as u32, texel.y as u32))
+    }
+
+    //Same as world_to_texel but without rounding or clamping to the map, for shapes that hang off the edge
+    pub fn world_to_texel_space(&self, transform: &GlobalTransform, point: Vec2) -> Vec2 {
+        let local = transform.affine().inverse().transform_point3(Vec3::new(point.x, transform.translation().y, point.y));
+        let pixel = self.local_to_pixel(local);
+        let (width, height) = self.texels();
+        Vec2::new(
+            pixel.x / self.pixel_width as f32 * width as f32,
+            pixel.y / self.pixel_height as f32 * height as f32,
+        )
+    }
+
+    pub fn grid_to_local(&self, x: f64, y: f64) -> Vec3 {
+        self.pixel_to_local(self.grid.grid_to_pixel(x, y))
+    }
+
+    pub fn grid_to_world(&self, transform: &GlobalTransform, point: &GridPoint) -> Vec2 {
+        let world = transform.transform_point(self.grid_to_local(point.x, point.y));
+        Vec2::new(world.x, world.z)
+    }
+
+    pub fn line_segments(&self, transform: &GlobalTransform, points: &[GridPoint]) -> Vec<(Vec2, Vec2)> {
+        points.windows(2)
+            .map(|pair| (self.grid_to_world(transform, &pair[0]), self.grid_to_world(transform, &pair[1])))
+            .collect()
+    }
+
+    //Every wall split into segments, on the ground plane in world space
+    pub fn wall_segments(&self, transform: &GlobalTransform) -> Vec<(Vec2, Vec2)> {
+        self.walls.iter()
+            .flat_map(|wall| self.line_segments(transform, &wall.points))
+            .collect()
+    }
+
+    //Only the doors that are currently shut
+    pub fn door_segments(&self, transform: &GlobalTransform, states: &MapDoors) -> Vec<(Vec2, Vec2)> {
+        self.doors.iter()
+            .enumerate()
+            .filter(|(i, door)| states.0.get(*i).copied().unwrap_or(door.closed))
+            .flat_map(|(_, door)| self.line_segments(transform, &door.bounds))
+            .collect()
+    }
+
+    //Where a point on the ground plane is in grid coordinates
+    pub fn world_to_grid(&self, transform: &GlobalTransform, point: Vec2) -> (f64, f64) {
+        let local = transform.affine().inverse().transform_point3(Vec3::new(point.x, 0., point.y));
+        let pixel = self.local_to_pixel(local);
+        self.grid.pixel_to_grid(pixel.x, pixel.y)
+    }
+
+    pub fn contains(&self, transform: &GlobalTransform, point: Vec2) -> bool {
+        let local = transform.affine().inverse().transform_point3(Vec3::new(point.x, 0., point.y));
+        let pixel = self.local_to_pixel(local);
+        pixel.x >= 0. && pixel.y >= 0. && pixel.x <= self.pixel_width as f32 && pixel.y <= self.pixel_height as f32
+    }
+
+    //Where a token of this radius should sit on the grid, odd sizes go in a cell's center and even sizes on a vertex
+    pub fn snap(&self, transform: &GlobalTransform, point: Vec2, radius: f32) -> Vec2 {
+        if self.grid.pixels_per <= 0. {
+            return point;
+        }
+        let (x, y) = self.world_to_grid(transform, point);
+        let cells = radius * 2. / CELL_SIZE;
+        let snap_axis = |value: f64| {
+            if cells < 1. {
+                //Tiny creatures snap to quarters of a cell so a few of them can share one
+                (value * 2.).floor() / 2. + 0.25
+            } else if cells.round() as u32 % 2 == 0 {
+                value.round()
+            } else {
+                value.floor() + 0.5
+            }
+        };
+        self.grid_to_world(transform, &GridPoint{ x: snap_axis(x), y: snap_axis(y) })
+    }
+
+    //The edges of the map, so sight always stops somewhere
+    pub fn bounds(&self, transform: &GlobalTransform) -> [Vec2; 4] {
+        let corner = |x: f32, y: f32| {
+            let world = transform.transform_point(self.pixel_to_local(Vec2::new(x, y)));
+            Vec2::new(world.x, world.z)
+        };
+        let w = self.pixel_width as f32;
+        let h = self.pixel_height as f32;
+        [corner(0., 0.), corner(w, 0.), corner(w, h), corner(0., h)]
+    }
+}
+
+//How big a single grid cell is in the world
+pub const CELL_SIZE: f32 = 5.;
+//How finely fog and sight are tracked across a grid cell
+pub const TEXELS_PER_CELL: f32 = 4.;
+
 #[derive(Serialize, Deserialize, Clone, Copy, Component, Eq, Hash, PartialEq)]
 pub struct MapLoaded;
 
@@ -114,7 +376,7 @@ pub struct MapLoaded;
 pub fn load_map(
     mut commands: Commands,
     mut ev_map_load: EventReader<MapLoad>,
-    mut maps: Query<(&Handle<Mesh>, &Handle<StandardMaterial>, Entity, &MapId, Without<MapLoaded>)>,
+    mut maps: Query<(&Handle<Mesh>, &Handle<StandardMaterial>, Entity, &MapId, &mut MapDoors, Without<MapLoaded>)>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut images: ResMut<Assets<Image>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
@@ -132,16 +394,42 @@ pub fn load_map(
             .expect("Unable to guess format")
             .decode()
             .expect("Malformed Image");
+        //Size the map from the image so the grid cells come out the right size
+        let pixels_per = if data.grid.pixels_per > 0. {
+            data.grid.pixels_per as f32
+        } else {
+            println!("Map has no grid size");
+            continue;
+        };
+        let width = image_data.width() as f32 / pixels_per * CELL_SIZE;
+        let height = image_data.height() as f32 / pixels_per * CELL_SIZE;
+        let dimensions = MapDimensions {
+            grid: data.grid.clone(),
+            walls: data.walls.clone(),
+            doors: data.doors.clone(),
+            lights: data.lights.clone(),
+            environment: data.environment.clone(),
+            pixel_width: image_data.width(),
+            pixel_height: image_data.height(),
+            size: Vec2::new(width, height),
+        };
         //Get the image in bevy's format
         let bevy_image = Image::from_dynamic(image_data, true);
         //Insert it into the images pool
         let image_handle = images.add(bevy_image);
 
-        for map in maps.iter_mut() {
+        for mut map in maps.iter_mut() {
             //Check if the id matches
             if *map.3 == ev.map_id {
 
-                commands.entity(map.2).insert(MapLoaded);
+                //Doors not already set by the encounter start how the map has them
+                let states = data.doors.iter()
+                    .enumerate()
+                    .map(|(i, door)| map.4.0.get(i).copied().unwrap_or(door.closed))
+                    .collect();
+                map.4.0 = states;
+
+                commands.entity(map.2).insert((MapLoaded, dimensions.clone()));
 
                 let Some(mat) = materials.get_mut(map.1) else {
                     println!("Failed to get mat");
@@ -150,8 +438,6 @@ pub fn load_map(
                 //Replace the material's image with the new one
                 mat.base_color_texture = Some(image_handle.clone());
 
-                let width = data.grid.width as f32 * 5.;
-                let height = data.grid.height as f32 * 5.;
                 println!("{width}, {height}");
                 //Create a new mesh of the correct size
                 let new_quad = shape::Quad {
@@ -169,3 +455,65 @@ pub fn load_map(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //A 10x10 cell map laid on the ground the same way load_map places it
+    fn test_map(pixels_per: f64) -> (MapDimensions, GlobalTransform) {
+        let dimensions = MapDimensions {
+            grid: MapGrid {
+                pixels_per,
+                width: 10,
+                height: 10,
+                ..default()
+            },
+            walls: Vec::new(),
+            doors: Vec::new(),
+            lights: Vec::new(),
+            environment: MapEnvironment::default(),
+            pixel_width: 1000,
+            pixel_height: 1000,
+            size: Vec2::splat(10. * CELL_SIZE),
+        };
+        let transform = Transform::from_xyz(0., 0., 0.)
+            .looking_at(Vec3::new(0., -1., 0.), Vec3::Y);
+        (dimensions, transform.into())
+    }
+
+    fn snapped(cells: f32, x: f64, y: f64) -> (f64, f64) {
+        let (dimensions, transform) = test_map(100.);
+        let point = dimensions.grid_to_world(&transform, &GridPoint{ x, y });
+        let snapped = dimensions.snap(&transform, point, cells * CELL_SIZE / 2.);
+        dimensions.world_to_grid(&transform, snapped)
+    }
+
+    fn assert_near((x, y): (f64, f64), (ex, ey): (f64, f64)) {
+        assert!((x - ex).abs() < 1e-3 && (y - ey).abs() < 1e-3, "({x}, {y}) isn't ({ex}, {ey})");
+    }
+
+    #[test]
+    fn odd_sizes_snap_to_cell_centers() {
+        assert_near(snapped(1., 3.3, 4.8), (3.5, 4.5));
+        assert_near(snapped(3., 6.9, 0.1), (6.5, 0.5));
+    }
+
+    #[test]
+    fn even_sizes_snap_to_vertices() {
+        assert_near(snapped(2., 3.3, 4.8), (3., 5.));
+        assert_near(snapped(4., 6.6, 6.4), (7., 6.));
+    }
+
+    #[test]
+    fn tiny_snaps_to_quarter_cells() {
+        assert_near(snapped(0.5, 3.3, 4.8), (3.25, 4.75));
+    }
+
+    #[test]
+    fn no_grid_leaves_point_alone() {
+        let (dimensions, transform) = test_map(0.);
+        let point = Vec2::new(1.3, -2.7);
+        assert_eq!(dimensions.snap(&transform, point, CELL_SIZE / 2.), point);
+    }
+}
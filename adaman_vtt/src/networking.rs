@@ -2,32 +2,50 @@ use bevy::prelude::*;
 use bevy_matchbox::prelude::*;
 use postcard::{from_bytes, to_stdvec};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
 
 use crate::orders;
+use crate::join;
+use crate::roles;
+use crate::tokens;
+use crate::ui;
 
 pub struct NetworkingPlugin;
 
 impl Plugin for NetworkingPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, open_socket)
+        app.add_event::<JoinRoom>()
+            .insert_resource(ConnectionStatus::new())
+            .insert_resource(PeerHandshakes{peers: HashMap::new()})
+            .insert_resource(PeerStrikes{strikes: HashMap::new(), ignored: HashSet::new(), last_rejected: HashMap::new()})
+            .add_systems(Update, open_socket)
+            .add_systems(Update, reconnect.before(open_socket))
             .add_event::<NetworkedCommandEvent>()
             .add_event::<ClientCommandEvent>()
             .add_event::<PeerConnected>()
             .add_event::<PeerDisconnected>()
-            .add_systems(Update, deal_with_connections)
+            .add_systems(
+                Update,
+                deal_with_connections
+                    .after(open_socket)
+                    .run_if(resource_exists::<MatchboxSocket<MultipleChannels>>()),
+            )
             .add_systems(Update, report_connections.after(deal_with_connections))
             .add_systems(Update, split_client_events.before(orders::recieve_orders))
             .add_systems(
                 Update,
                 send_networked_events
                     .after(split_client_events)
-                    .after(deal_with_connections),
+                    .after(deal_with_connections)
+                    .run_if(resource_exists::<MatchboxSocket<MultipleChannels>>()),
             )
             .add_systems(
                 Update,
                 recieve_networked_events
                     .after(deal_with_connections)
-                    .before(orders::recieve_orders),
+                    .before(orders::recieve_orders)
+                    .run_if(resource_exists::<MatchboxSocket<MultipleChannels>>()),
             );
     }
 }
@@ -37,16 +55,96 @@ pub struct LocalPeerId {
     pub id: PeerId,
 }
 
-fn open_socket(mut commands: Commands) {
-    let room_url = "wss://matchbox-server-woj7mv63ka-uc.a.run.app/adamantvtt";
-    //let room_url = "ws://127.0.0.1:3635/adamantvtt";
+//Sent by the join screen once the user has chosen where to connect
+#[derive(Event)]
+pub struct JoinRoom {
+    pub settings: join::JoinSettings,
+}
+
+fn open_socket(
+    mut commands: Commands,
+    mut ev_join: EventReader<JoinRoom>,
+    mut status: ResMut<ConnectionStatus>,
+) {
+    for ev in ev_join.read() {
+        let room_url = ev.settings.room_url();
+        println!("Joining room: {room_url}");
 
-    let socket: MatchboxSocket<MultipleChannels> = WebRtcSocketBuilder::new(room_url)
+        commands.insert_resource(build_socket(room_url.clone()));
+        status.room_url = Some(room_url);
+        status.local_name = ev.settings.name.trim().to_string();
+        status.state = ConnectionState::Connecting;
+        status.attempts = 0;
+    }
+}
+
+fn build_socket(room_url: String) -> MatchboxSocket<MultipleChannels> {
+    WebRtcSocketBuilder::new(room_url)
         .add_channel(ChannelConfig::reliable())
         .add_channel(ChannelConfig::unreliable())
-        .into();
-    
-    commands.insert_resource(socket);
+        .into()
+}
+
+const MAX_RECONNECT_DELAY: f32 = 30.;
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum ConnectionState {
+    NotJoined,
+    Connecting,
+    Connected,
+    Reconnecting,
+}
+
+#[derive(Resource)]
+pub struct ConnectionStatus {
+    pub state: ConnectionState,
+    pub attempts: u32,
+    pub retry: Timer,
+    pub peers: HashSet<PeerId>,
+    room_url: Option<String>,
+    local_name: String,
+}
+
+impl ConnectionStatus {
+    fn new() -> ConnectionStatus {
+        ConnectionStatus {
+            state: ConnectionState::NotJoined,
+            attempts: 0,
+            retry: Timer::default(),
+            peers: HashSet::new(),
+            room_url: None,
+            local_name: "".to_string(),
+        }
+    }
+
+    //Back off 1, 2, 4... seconds up to the max between attempts
+    fn schedule_retry(&mut self) {
+        let delay = 2f32.powi(self.attempts as i32).min(MAX_RECONNECT_DELAY);
+        self.attempts += 1;
+        self.retry = Timer::new(Duration::from_secs_f32(delay), TimerMode::Once);
+        self.state = ConnectionState::Reconnecting;
+    }
+}
+
+fn reconnect(
+    mut commands: Commands,
+    mut status: ResMut<ConnectionStatus>,
+    time: Res<Time>,
+    mut events: EventWriter<ui::InsertLog>,
+) {
+    if status.state != ConnectionState::Reconnecting {
+        return;
+    }
+    if !status.retry.tick(time.delta()).just_finished() {
+        return;
+    }
+    let Some(room_url) = status.room_url.clone() else {
+        return;
+    };
+    let attempt = status.attempts;
+    events.send(ui::InsertLog::new(format!("Reconnecting to server, attempt {attempt}")));
+    commands.insert_resource(build_socket(room_url));
+    status.state = ConnectionState::Connecting;
 }
 
 #[derive(Event)]
@@ -59,11 +157,25 @@ fn deal_with_connections(
     mut commands: Commands,
     mut connection: ResMut<MatchboxSocket<MultipleChannels>>,
     local_peer_id: Option<Res<LocalPeerId>>,
+    mut status: ResMut<ConnectionStatus>,
+    mut handshakes: ResMut<PeerHandshakes>,
     mut ev_connected: EventWriter<PeerConnected>,
     mut ev_disconnected: EventWriter<PeerDisconnected>,
+    mut events: EventWriter<ui::InsertLog>,
 ) {
     let updated_peers = match connection.try_update_peers() {
-        Err(_x) => panic!("Disconnected from server"),
+        Err(_x) => {
+            //Drop the dead socket and everyone we knew through it, they'll come back once we reconnect
+            commands.remove_resource::<MatchboxSocket<MultipleChannels>>();
+            commands.remove_resource::<LocalPeerId>();
+            handshakes.peers.clear();
+            for peer_id in status.peers.drain().collect::<Vec<PeerId>>() {
+                ev_disconnected.send(PeerDisconnected(peer_id));
+            }
+            status.schedule_retry();
+            events.send(ui::InsertLog::new("Disconnected from server".to_string()));
+            return;
+        },
         Ok(x) => x,
     };
     if local_peer_id.is_none() {
@@ -71,14 +183,21 @@ fn deal_with_connections(
             commands.insert_resource(LocalPeerId{
                 id,
             });
+            status.state = ConnectionState::Connected;
+            status.attempts = 0;
         }
     }
     for (peer_id, peer_state) in updated_peers {
         match peer_state {
             PeerState::Connected => {
+                //The hello goes out before anything else this frame
+                send_hello(&mut connection, peer_id, &status.local_name);
+                status.peers.insert(peer_id);
                 ev_connected.send(PeerConnected(peer_id));
             },
             PeerState::Disconnected => {
+                handshakes.peers.remove(&peer_id);
+                status.peers.remove(&peer_id);
                 ev_disconnected.send(PeerDisconnected(peer_id));
             },
         };
@@ -115,6 +234,143 @@ struct NetworkPacket {
     pub order: orders::OrderEvent,
 }
 
+//First thing sent to every peer, kept as json so any version can read it
+#[derive(Serialize, Deserialize)]
+struct HelloPacket {
+    protocol_version: u16,
+    #[serde(default)]
+    app_version: String,
+    #[serde(default)]
+    name: String,
+}
+
+enum Packet {
+    Hello(HelloPacket),
+    Order(NetworkPacket),
+}
+
+//Every packet starts with the magic, protocol version and kind, followed by the body
+//Orders are postcard encoded, hellos are json
+const PACKET_MAGIC: [u8; 4] = *b"AVTT";
+//Postcard isn't self describing, so this has to go up in the same change as any command or packet that changes shape
+//Builds between 2 and 3 changed commands without bumping it, so they all say 2 and can't be trusted with each other
+//1 postcard packets, 2 hellos, 3 drag previews through conditions, 4 join times in role announcements,
+//5 FNV-1a hashes, 6 snapshot parts, 7 door toggles name a token, 8 encounter lighting, 9 owners by peer id
+pub const PROTOCOL_VERSION: u16 = 9;
+pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+const HEADER_LEN: usize = PACKET_MAGIC.len() + 3;
+const KIND_HELLO: u8 = 0;
+const KIND_ORDER: u8 = 1;
+
+pub enum PacketError {
+    BadMagic,
+    VersionMismatch(u16),
+    Malformed,
+}
+
+fn encode_packet(kind: u8, body: &[u8]) -> Box<[u8]> {
+    let mut arr = Vec::<u8>::with_capacity(HEADER_LEN + body.len());
+    arr.extend_from_slice(&PACKET_MAGIC);
+    arr.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+    arr.push(kind);
+    arr.extend_from_slice(body);
+    arr.into_boxed_slice()
+}
+
+impl NetworkPacket {
+    fn encode(&self) -> Option<Box<[u8]>> {
+        let body = to_stdvec(self).ok()?;
+        Some(encode_packet(KIND_ORDER, &body))
+    }
+}
+
+impl HelloPacket {
+    fn encode(&self) -> Option<Box<[u8]>> {
+        let body = serde_json::to_vec(self).ok()?;
+        Some(encode_packet(KIND_HELLO, &body))
+    }
+}
+
+impl Packet {
+    fn decode(packet: &[u8]) -> Result<Packet, PacketError> {
+        if packet.len() < HEADER_LEN || packet[..PACKET_MAGIC.len()] != PACKET_MAGIC {
+            return Err(PacketError::BadMagic);
+        }
+        let version = u16::from_le_bytes([packet[PACKET_MAGIC.len()], packet[PACKET_MAGIC.len() + 1]]);
+        let body = &packet[HEADER_LEN..];
+        match packet[HEADER_LEN - 1] {
+            KIND_HELLO => serde_json::from_slice::<HelloPacket>(body)
+                .map(Packet::Hello)
+                .map_err(|_| PacketError::Malformed),
+            KIND_ORDER if version != PROTOCOL_VERSION => Err(PacketError::VersionMismatch(version)),
+            KIND_ORDER => from_bytes::<NetworkPacket>(body)
+                .map(Packet::Order)
+                .map_err(|_| PacketError::Malformed),
+            _ => Err(PacketError::Malformed),
+        }
+    }
+}
+
+pub struct PeerInfo {
+    pub compatible: bool,
+    pub app_version: String,
+    pub name: String,
+}
+
+//Peers we've heard a hello from
+#[derive(Resource)]
+pub struct PeerHandshakes {
+    pub peers: HashMap<PeerId, PeerInfo>,
+}
+
+impl PeerHandshakes {
+    fn is_compatible(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).is_some_and(|x| x.compatible)
+    }
+
+    fn is_refused(&self, peer_id: &PeerId) -> bool {
+        self.peers.get(peer_id).is_some_and(|x| !x.compatible)
+    }
+}
+
+fn send_hello(
+    connection: &mut MatchboxSocket<MultipleChannels>,
+    peer_id: PeerId,
+    name: &str,
+) {
+    let hello = HelloPacket {
+        protocol_version: PROTOCOL_VERSION,
+        app_version: APP_VERSION.to_string(),
+        name: name.to_string(),
+    };
+    let Some(arr) = hello.encode() else {
+        return;
+    };
+    connection.get_channel(0).unwrap().send(arr, peer_id);
+}
+
+fn recieve_hello(
+    peer_id: PeerId,
+    hello: HelloPacket,
+    handshakes: &mut PeerHandshakes,
+    events: &mut EventWriter<ui::InsertLog>,
+) {
+    let compatible = hello.protocol_version == PROTOCOL_VERSION;
+    if compatible {
+        println!("Handshake with {} ({peer_id}) running {}", hello.name, hello.app_version);
+    } else {
+        events.send(ui::InsertLog::new(format!(
+            "Refused {}: they are running version {} (protocol {}), we are running {} (protocol {})",
+            hello.name, hello.app_version, hello.protocol_version, APP_VERSION, PROTOCOL_VERSION,
+        )));
+    }
+    handshakes.peers.insert(peer_id, PeerInfo {
+        compatible,
+        app_version: hello.app_version,
+        name: hello.name,
+    });
+}
+
 #[derive(Event)]
 pub struct ClientCommandEvent {
     pub order: orders::OrderEvent,
@@ -156,15 +412,20 @@ fn split_client_events(
 fn send_networked_events(
     mut ev_networked: EventReader<NetworkedCommandEvent>,
     mut connection: ResMut<MatchboxSocket<MultipleChannels>>,
+    handshakes: Res<PeerHandshakes>,
 ) {
     for ev in ev_networked.read() {
+        let packet = NetworkPacket {
+            order: ev.order.clone(),
+        };
+        let Some(arr) = packet.encode() else {
+            println!("Unable to encode packet");
+            continue;
+        };
         let ids = Vec::from_iter(connection.connected_peers());
         for peer_id in ids {
-            if ev.peer_id.valid_for_peer(&peer_id) {
-                let packet = NetworkPacket {
-                    order: ev.order.clone(),
-                };
-                let arr = serde_json::to_vec(&packet).unwrap().into_boxed_slice();
+            if ev.peer_id.valid_for_peer(&peer_id) && !handshakes.is_refused(&peer_id) {
+                let arr = arr.clone();
                 let channel = match ev.reliability {
                     NetworkReliability::Reliable => 0,
                     NetworkReliability::Unreliable => 1,
@@ -175,22 +436,135 @@ fn send_networked_events(
     }
 }
 
+//Biggest packet we'll bother decoding, file sections and snapshot parts are 16KiB so this leaves plenty of room
+const MAX_PACKET_BYTES: usize = 256 * 1024;
+//How many bad packets a peer can send before we stop listening to them
+const MAX_STRIKES: u32 = 20;
+
+//Bad packets seen from each peer
+#[derive(Resource)]
+pub struct PeerStrikes {
+    pub strikes: HashMap<PeerId, u32>,
+    pub ignored: HashSet<PeerId>,
+    //When we last told the user about a command they weren't allowed to send
+    last_rejected: HashMap<PeerId, f32>,
+}
+
+//Seconds between log messages about the same peer's rejected commands
+const REJECT_LOG_INTERVAL: f32 = 10.;
+
+impl PeerStrikes {
+    //Unauthorized commands are usually just out of date, like a drag still going when the token changes hands
+    //They're dropped without a strike, and only mentioned now and then
+    fn should_log_rejection(&mut self, peer_id: PeerId, now: f32) -> bool {
+        let last = self.last_rejected.entry(peer_id).or_insert(f32::NEG_INFINITY);
+        if now - *last < REJECT_LOG_INTERVAL {
+            return false;
+        }
+        *last = now;
+        true
+    }
+
+    fn strike(
+        &mut self,
+        peer_id: PeerId,
+        reason: &str,
+        events: &mut EventWriter<ui::InsertLog>,
+    ) {
+        let strikes = self.strikes.entry(peer_id).or_insert(0);
+        *strikes += 1;
+        if *strikes >= MAX_STRIKES && self.ignored.insert(peer_id) {
+            events.send(ui::InsertLog::new(format!("Ignoring peer {peer_id} after too many bad packets, last was {reason}")));
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn recieve_networked_events(
     mut connection: ResMut<MatchboxSocket<MultipleChannels>>,
     mut ev_order: EventWriter<orders::OrderEvent>,
+    mut roles: ResMut<roles::Roles>,
+    owners: Query<(&tokens::TokenId, &tokens::TokenOwner)>,
+    mut handshakes: ResMut<PeerHandshakes>,
+    mut strikes: ResMut<PeerStrikes>,
+    mut events: EventWriter<ui::InsertLog>,
+    time: Res<Time>,
 ) {
     //Reliable
-    let recieved = connection.get_channel(0).unwrap().receive();
-    for (peer_id, packet) in recieved {
-        let remote_order = serde_json::from_slice::<NetworkPacket>(&packet).unwrap();
-        ev_order.send(remote_order.order);
-        println!("Recieved from: {peer_id}");
-    }
+    let mut recieved: Vec<_> = connection.get_channel(0).unwrap().receive()
+        .into_iter()
+        .map(|(peer_id, packet)| (peer_id, packet, NetworkReliability::Reliable))
+        .collect();
     //Unreliable
-    let recieved = connection.get_channel(1).unwrap().receive();
-    for (peer_id, packet) in recieved {
-        let remote_order = serde_json::from_slice::<NetworkPacket>(&packet).unwrap();
+    recieved.extend(connection.get_channel(1).unwrap().receive()
+        .into_iter()
+        .map(|(peer_id, packet)| (peer_id, packet, NetworkReliability::Unreliable)));
+    for (peer_id, packet, reliability) in recieved {
+        if strikes.ignored.contains(&peer_id) {
+            continue;
+        }
+        if packet.len() > MAX_PACKET_BYTES {
+            strikes.strike(peer_id, "oversized", &mut events);
+            continue;
+        }
+        let remote_order = match Packet::decode(&packet) {
+            Ok(Packet::Order(x)) => x,
+            Ok(Packet::Hello(hello)) => {
+                recieve_hello(peer_id, hello, &mut handshakes, &mut events);
+                continue;
+            },
+            //Already reported when they said hello
+            Err(PacketError::VersionMismatch(_)) => continue,
+            Err(PacketError::BadMagic) => {
+                strikes.strike(peer_id, "bad header", &mut events);
+                continue;
+            },
+            Err(PacketError::Malformed) => {
+                strikes.strike(peer_id, "malformed", &mut events);
+                continue;
+            },
+        };
+        //Nothing counts until they've said hello with a version we understand
+        if !handshakes.is_compatible(&peer_id) {
+            continue;
+        }
+        if matches!(reliability, NetworkReliability::Unreliable)
+            && !remote_order.order.command.allows_unreliable() {
+            strikes.strike(peer_id, "unexpected", &mut events);
+            continue;
+        }
+        if !is_valid_sender(&peer_id, &remote_order.order.command) {
+            strikes.strike(peer_id, "spoofed sender", &mut events);
+            continue;
+        }
+        if !roles.is_authorized(&peer_id, &remote_order.order.command, &owners) {
+            if strikes.should_log_rejection(peer_id, time.elapsed_seconds()) {
+                let name = roles.peer_name(&peer_id).cloned().unwrap_or(peer_id.to_string());
+                events.send(ui::InsertLog::new(format!("Rejected command from {name}")));
+            }
+            continue;
+        }
+        if let orders::Command::AnnounceRole(cmd) = &remote_order.order.command {
+            roles.register(peer_id, cmd.role, cmd.name.clone(), time.elapsed_seconds());
+        }
         ev_order.send(remote_order.order);
-        println!("Recieved from: {peer_id}");
     }
 }
+
+//Commands that say who they're from have to actually be from that peer
+fn is_valid_sender(peer_id: &PeerId, command: &orders::Command) -> bool {
+    let from = match command {
+        orders::Command::AnnounceRole(cmd) => cmd.peer_id,
+        orders::Command::Move(cmd) => cmd.peer_id,
+        orders::Command::DragPreview(cmd) => cmd.peer_id,
+        orders::Command::Message(cmd) => cmd.from,
+        orders::Command::RequestUploadLock(cmd) => cmd.peer_id,
+        orders::Command::SuccessfulUploadLock(cmd) => cmd.peer_id,
+        orders::Command::RequestData(cmd) => cmd.peer_id,
+        orders::Command::RecieveData(cmd) => cmd.peer_id,
+        orders::Command::UnlockUpload(cmd) => cmd.peer_id,
+        orders::Command::UploadAvailable(cmd) => cmd.peer_id,
+        _ => return true,
+    };
+    from == *peer_id
+}
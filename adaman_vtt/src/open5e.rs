@@ -121,6 +121,16 @@ impl From<Result> for tokens::TokenData {
             hit_points: x.hit_points,
             type_field: x.type_field,
             img: x.img_main,
+            senses: x.senses,
+            stats: tokens::TokenStats{
+                strength: x.strength,
+                dexterity: x.dexterity,
+                constitution: x.constitution,
+                intelligence: x.intelligence,
+                wisdom: x.wisdom,
+                charisma: x.charisma,
+            },
+            ..Default::default()
         }
     }
 }
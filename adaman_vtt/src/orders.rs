@@ -1,5 +1,7 @@
 use bevy::prelude::*;
+use bevy::ecs::system::SystemParam;
 use bevy::window::RequestRedraw;
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use bevy_matchbox::prelude::PeerId;
 use std::sync::Arc;
@@ -9,6 +11,16 @@ use crate::tokens;
 use crate::fileload;
 use crate::filetransfer;
 use crate::ui;
+use crate::roles;
+use crate::bank;
+use crate::encounters;
+use crate::lighting;
+use crate::fog;
+use crate::vision;
+use crate::health;
+use crate::initiative;
+use crate::conditions;
+use crate::doors;
 
 pub struct OrdersPlugin;
 
@@ -19,6 +31,10 @@ impl Plugin for OrdersPlugin {
 
             .add_event::<MoveCommand>()
             .add_systems(Update, recieve_move.after(recieve_orders))
+
+            .init_resource::<DragSequences>()
+            .add_event::<DragPreviewCommand>()
+            .add_systems(Update, recieve_drag_preview.after(recieve_orders))
             
             .add_event::<CreateTokenCommand>()
             .add_systems(Update, recieve_create_token.after(recieve_orders))
@@ -46,6 +62,45 @@ impl Plugin for OrdersPlugin {
 
             .add_event::<LoadEncounterCommand>()
             .add_systems(Update, recieve_load_encounter.after(recieve_orders))
+
+            .add_event::<AnnounceRoleCommand>()
+            .add_systems(Update, recieve_announce_role.after(recieve_orders))
+
+            .add_event::<AssignOwnerCommand>()
+            .add_systems(Update, recieve_assign_owner.after(recieve_orders))
+
+            .add_event::<SyncEncounterCommand>()
+            .add_systems(Update, recieve_sync_encounter.after(recieve_orders))
+
+            .add_event::<ToggleDoorCommand>()
+            .add_systems(Update, recieve_toggle_door.after(recieve_orders))
+
+            .add_event::<SetLightingCommand>()
+            .add_systems(Update, recieve_set_lighting.after(recieve_orders))
+
+            .add_event::<FogCommand>()
+            .add_systems(Update, recieve_fog.after(recieve_orders))
+
+            .add_event::<DeleteTokenCommand>()
+            .add_systems(Update, recieve_delete_token.after(recieve_orders))
+
+            .add_event::<DuplicateTokenCommand>()
+            .add_systems(Update, recieve_duplicate_token.after(recieve_orders).before(recieve_create_token))
+
+            .add_event::<LockTokenCommand>()
+            .add_systems(Update, recieve_lock_token.after(recieve_orders))
+
+            .add_event::<ChangeTokenDataCommand>()
+            .add_systems(Update, recieve_change_token_data.after(recieve_orders))
+
+            .add_event::<SetHealthCommand>()
+            .add_systems(Update, recieve_set_health.after(recieve_orders))
+
+            .add_event::<SetInitiativeCommand>()
+            .add_systems(Update, recieve_set_initiative.after(recieve_orders))
+
+            .add_event::<SetConditionsCommand>()
+            .add_systems(Update, recieve_set_conditions.after(recieve_orders))
         ;
     }
 }
@@ -58,6 +113,7 @@ pub struct OrderEvent {
 #[derive(Serialize, Deserialize, Clone)]
 pub enum Command {
     Move(MoveCommand),
+    DragPreview(DragPreviewCommand),
     CreateToken(CreateTokenCommand),
     CreateMap(CreateMapCommand),
     LoadEncounter(LoadEncounterCommand),
@@ -68,22 +124,95 @@ pub enum Command {
     UnlockUpload(UnlockUploadCommand),
     UploadAvailable(UploadAvailableCommand),
     Message(ui::RecieveMessage),
+    AnnounceRole(AnnounceRoleCommand),
+    AssignOwner(AssignOwnerCommand),
+    SyncEncounter(SyncEncounterCommand),
+    ToggleDoor(ToggleDoorCommand),
+    SetLighting(SetLightingCommand),
+    Fog(FogCommand),
+    DeleteToken(DeleteTokenCommand),
+    DuplicateToken(DuplicateTokenCommand),
+    LockToken(LockTokenCommand),
+    ChangeTokenData(ChangeTokenDataCommand),
+    SetHealth(SetHealthCommand),
+    SetInitiative(SetInitiativeCommand),
+    SetConditions(SetConditionsCommand),
+}
+
+impl Command {
+    //Everything except drag previews has to come over the reliable channel
+    pub fn allows_unreliable(&self) -> bool {
+        matches!(self, Command::DragPreview(_))
+    }
+
+    pub fn authority(&self) -> roles::Authority {
+        match self {
+            Command::Move(cmd) => roles::Authority::TokenOwner(cmd.id),
+            Command::DragPreview(cmd) => roles::Authority::TokenOwner(cmd.id),
+            Command::SetHealth(cmd) => roles::Authority::TokenOwner(cmd.id),
+            Command::SetConditions(cmd) => roles::Authority::TokenOwner(cmd.id),
+            //Players open doors with one of their tokens, the GM doesn't need one
+            Command::ToggleDoor(cmd) => match cmd.token {
+                Some(id) => roles::Authority::TokenOwner(id),
+                None => roles::Authority::GameMaster,
+            },
+            Command::CreateToken(_)
+            | Command::CreateMap(_)
+            | Command::LoadEncounter(_)
+            | Command::AssignOwner(_)
+            | Command::SyncEncounter(_)
+            | Command::SetLighting(_)
+            | Command::Fog(_)
+            | Command::DeleteToken(_)
+            | Command::DuplicateToken(_)
+            | Command::LockToken(_)
+            | Command::ChangeTokenData(_)
+            | Command::SetInitiative(_) => roles::Authority::GameMaster,
+            Command::RequestData(_)
+            | Command::RequestUploadLock(_)
+            | Command::SuccessfulUploadLock(_)
+            | Command::RecieveData(_)
+            | Command::UnlockUpload(_)
+            | Command::UploadAvailable(_)
+            | Command::Message(_)
+            | Command::AnnounceRole(_) => roles::Authority::Anyone,
+        }
+    }
+}
+
+//One writer per command, grouped so recieve_orders stays under the system parameter limit
+#[derive(SystemParam)]
+pub struct OrderWriters<'w> {
+    ev_move: EventWriter<'w, MoveCommand>,
+    ev_drag_preview: EventWriter<'w, DragPreviewCommand>,
+    ev_create_token: EventWriter<'w, CreateTokenCommand>,
+    ev_create_map: EventWriter<'w, CreateMapCommand>,
+    ev_load_encounter: EventWriter<'w, LoadEncounterCommand>,
+    ev_request_data: EventWriter<'w, RequestDataCommand>,
+    ev_request_upload_lock: EventWriter<'w, RequestUploadLockCommand>,
+    ev_successful_upload_locked: EventWriter<'w, SuccessfulUploadLockedCommand>,
+    ev_recieve_data: EventWriter<'w, RecieveDataCommand>,
+    ev_unlock_upload: EventWriter<'w, UnlockUploadCommand>,
+    ev_upload_available: EventWriter<'w, UploadAvailableCommand>,
+    ev_message: EventWriter<'w, ui::RecieveMessage>,
+    ev_announce_role: EventWriter<'w, AnnounceRoleCommand>,
+    ev_assign_owner: EventWriter<'w, AssignOwnerCommand>,
+    ev_sync_encounter: EventWriter<'w, SyncEncounterCommand>,
+    ev_toggle_door: EventWriter<'w, ToggleDoorCommand>,
+    ev_set_lighting: EventWriter<'w, SetLightingCommand>,
+    ev_fog: EventWriter<'w, FogCommand>,
+    ev_delete_token: EventWriter<'w, DeleteTokenCommand>,
+    ev_duplicate_token: EventWriter<'w, DuplicateTokenCommand>,
+    ev_lock_token: EventWriter<'w, LockTokenCommand>,
+    ev_change_token_data: EventWriter<'w, ChangeTokenDataCommand>,
+    ev_set_health: EventWriter<'w, SetHealthCommand>,
+    ev_set_initiative: EventWriter<'w, SetInitiativeCommand>,
+    ev_set_conditions: EventWriter<'w, SetConditionsCommand>,
 }
 
-#[allow(clippy::too_many_arguments)]
 pub fn recieve_orders(
     mut ev_orders: EventReader<OrderEvent>,
-    mut ev_move: EventWriter<MoveCommand>,
-    mut ev_create_token: EventWriter<CreateTokenCommand>,
-    mut ev_create_map: EventWriter<CreateMapCommand>,
-    mut ev_load_encounter: EventWriter<LoadEncounterCommand>,
-    mut ev_request_data: EventWriter<RequestDataCommand>,
-    mut ev_request_upload_lock: EventWriter<RequestUploadLockCommand>,
-    mut ev_successful_upload_locked: EventWriter<SuccessfulUploadLockedCommand>,
-    mut ev_recieve_data: EventWriter<RecieveDataCommand>,
-    mut ev_unlock_upload: EventWriter<UnlockUploadCommand>,
-    mut ev_upload_available: EventWriter<UploadAvailableCommand>,
-    mut ev_message: EventWriter<ui::RecieveMessage>,
+    mut writers: OrderWriters,
 ) {
     for ord_ev in ev_orders.read() {
         //match &ord_ev.command {
@@ -96,17 +225,31 @@ pub fn recieve_orders(
             //Command::RecieveData(_cmd) => println!("Recieve Data"),
         //}
         match &ord_ev.command {
-            Command::Move(cmd) => ev_move.send(*cmd),
-            Command::CreateToken(cmd) => ev_create_token.send(cmd.clone()),
-            Command::CreateMap(cmd) => ev_create_map.send(cmd.clone()),
-            Command::LoadEncounter(cmd) => ev_load_encounter.send(cmd.clone()),
-            Command::RequestData(cmd) => ev_request_data.send(cmd.clone()),
-            Command::RequestUploadLock(cmd) => ev_request_upload_lock.send(cmd.clone()),
-            Command::SuccessfulUploadLock(cmd) => ev_successful_upload_locked.send(cmd.clone()),
-            Command::RecieveData(cmd) => ev_recieve_data.send(cmd.clone()),
-            Command::UnlockUpload(cmd) => ev_unlock_upload.send(cmd.clone()),
-            Command::UploadAvailable(cmd) => ev_upload_available.send(cmd.clone()),
-            Command::Message(cmd) => ev_message.send(cmd.clone()),
+            Command::Move(cmd) => writers.ev_move.send(*cmd),
+            Command::DragPreview(cmd) => writers.ev_drag_preview.send(*cmd),
+            Command::CreateToken(cmd) => writers.ev_create_token.send(cmd.clone()),
+            Command::CreateMap(cmd) => writers.ev_create_map.send(cmd.clone()),
+            Command::LoadEncounter(cmd) => writers.ev_load_encounter.send(cmd.clone()),
+            Command::RequestData(cmd) => writers.ev_request_data.send(cmd.clone()),
+            Command::RequestUploadLock(cmd) => writers.ev_request_upload_lock.send(cmd.clone()),
+            Command::SuccessfulUploadLock(cmd) => writers.ev_successful_upload_locked.send(cmd.clone()),
+            Command::RecieveData(cmd) => writers.ev_recieve_data.send(cmd.clone()),
+            Command::UnlockUpload(cmd) => writers.ev_unlock_upload.send(cmd.clone()),
+            Command::UploadAvailable(cmd) => writers.ev_upload_available.send(cmd.clone()),
+            Command::Message(cmd) => writers.ev_message.send(cmd.clone()),
+            Command::AnnounceRole(cmd) => writers.ev_announce_role.send(cmd.clone()),
+            Command::AssignOwner(cmd) => writers.ev_assign_owner.send(cmd.clone()),
+            Command::SyncEncounter(cmd) => writers.ev_sync_encounter.send(cmd.clone()),
+            Command::ToggleDoor(cmd) => writers.ev_toggle_door.send(*cmd),
+            Command::SetLighting(cmd) => writers.ev_set_lighting.send(*cmd),
+            Command::Fog(cmd) => writers.ev_fog.send(cmd.clone()),
+            Command::DeleteToken(cmd) => writers.ev_delete_token.send(*cmd),
+            Command::DuplicateToken(cmd) => writers.ev_duplicate_token.send(*cmd),
+            Command::LockToken(cmd) => writers.ev_lock_token.send(*cmd),
+            Command::ChangeTokenData(cmd) => writers.ev_change_token_data.send(cmd.clone()),
+            Command::SetHealth(cmd) => writers.ev_set_health.send(*cmd),
+            Command::SetInitiative(cmd) => writers.ev_set_initiative.send(cmd.clone()),
+            Command::SetConditions(cmd) => writers.ev_set_conditions.send(cmd.clone()),
         }
     }
 }
@@ -116,16 +259,21 @@ pub struct MoveCommand {
     pub x: f32,
     pub y: f32,
     pub id: tokens::TokenId,
+    //Who moved it and where they were in their drag, so late previews from that drag are ignored
+    pub peer_id: PeerId,
+    pub sequence: u32,
 }
 
 fn recieve_move(
     mut ev_move: EventReader<MoveCommand>,
-    mut tokens: Query<(&tokens::TokenId, &mut Transform, Option<&tokens::StrippedTokenData>)>,
+    mut tokens: Query<(&tokens::TokenId, &mut Transform, &tokens::TokenLock)>,
+    mut sequences: ResMut<DragSequences>,
     mut event: EventWriter<RequestRedraw>,
 ) {
     for mov_ev in ev_move.read() {
+        sequences.update(mov_ev.peer_id, mov_ev.id, mov_ev.sequence);
         for mut token in tokens.iter_mut() {
-            if token.0.0 == mov_ev.id.0 {
+            if token.0.0 == mov_ev.id.0 && !token.2.0 {
                 token.1.translation.x = mov_ev.x;
                 token.1.translation.z = mov_ev.y;
                 event.send(RequestRedraw)
@@ -134,12 +282,67 @@ fn recieve_move(
     }
 }
 
+//Sent unreliably while a token is being dragged, only the final MoveCommand is guaranteed to arrive
+#[derive(Event, Serialize, Deserialize, Copy, Clone)]
+pub struct DragPreviewCommand {
+    pub x: f32,
+    pub y: f32,
+    pub id: tokens::TokenId,
+    pub peer_id: PeerId,
+    pub sequence: u32,
+}
+
+//The newest drag sequence seen from each peer for each token
+#[derive(Resource, Default)]
+pub struct DragSequences(HashMap<(PeerId, tokens::TokenId), u32>);
+
+impl DragSequences {
+    //Returns false if the sequence is older than one we've already seen
+    fn update(&mut self, peer_id: PeerId, id: tokens::TokenId, sequence: u32) -> bool {
+        let last = self.0.entry((peer_id, id)).or_insert(0);
+        if sequence <= *last {
+            return false;
+        }
+        *last = sequence;
+        true
+    }
+}
+
+fn recieve_drag_preview(
+    mut ev_drag_preview: EventReader<DragPreviewCommand>,
+    mut tokens: Query<(&tokens::TokenId, &mut Transform, &tokens::TokenLock)>,
+    mut sequences: ResMut<DragSequences>,
+    mut event: EventWriter<RequestRedraw>,
+) {
+    for ev in ev_drag_preview.read() {
+        if !sequences.update(ev.peer_id, ev.id, ev.sequence) {
+            continue;
+        }
+        for (id, mut transform, lock) in tokens.iter_mut() {
+            if *id == ev.id && !lock.0 {
+                transform.translation.x = ev.x;
+                transform.translation.z = ev.y;
+                event.send(RequestRedraw)
+            }
+        }
+    }
+}
+
 #[derive(Event, Serialize, Deserialize, Clone)]
 pub struct CreateTokenCommand {
     pub x: f32,
     pub y: f32,
     pub id: tokens::TokenId,
     pub load_identifier: fileload::LoadIdentifier,
+    //Older saves had a name here, those tokens go back to the GM until they're given out again
+    #[serde(default)]
+    pub owned_by: Option<tokens::Owner>,
+    #[serde(default)]
+    pub locked: bool,
+    #[serde(default)]
+    pub health: Option<health::TokenHealth>,
+    #[serde(default)]
+    pub conditions: conditions::TokenConditions,
 }
 
 fn recieve_create_token(
@@ -150,13 +353,20 @@ fn recieve_create_token(
     mut ev_load: EventWriter<fileload::LoadRequest>,
 ) {
     for ev in ev_create_token.read() {
-        commands.spawn(tokens::TokenBundle::new(
+        let mut token = commands.spawn(tokens::TokenBundle::new(
             ev.id,
             ev.load_identifier.clone(),
+            tokens::TokenOwner(ev.owned_by.clone()),
+            tokens::TokenLock(ev.locked),
             Vec3::new(ev.x, 0.5, ev.y),
             &mut meshes,
             &mut materials,
         ));
+        token.insert(ev.conditions.clone());
+        //Without saved health the token starts at full once its data loads
+        if let Some(health) = ev.health {
+            token.insert(health);
+        }
         ev_load.send(
             fileload::LoadRequest{
                 id: ev.load_identifier.clone(),
@@ -187,6 +397,7 @@ fn recieve_upload_request(
 #[derive(Event, Serialize, Deserialize, Clone)]
 pub struct SuccessfulUploadLockedCommand {
     pub peer_id: PeerId,
+    pub data_id: bank::DataId,
 }
 
 fn recieve_successful_upload_lock(
@@ -196,6 +407,7 @@ fn recieve_successful_upload_lock(
     for ev in ev_order.read() {
         ev_pass.send(filetransfer::SuccessfulUploadLock{
             peer_id: ev.peer_id,
+            data_id: ev.data_id,
         });
     }
 }
@@ -241,14 +453,20 @@ fn recieve_recieve_data(
 }
 
 #[derive(Event, Serialize, Deserialize, Clone)]
-pub struct UnlockUploadCommand;
+pub struct UnlockUploadCommand {
+    pub peer_id: PeerId,
+    pub data_id: bank::DataId,
+}
 
 fn recieve_unlock_upload(
     mut ev_order: EventReader<UnlockUploadCommand>,
     mut ev_pass: EventWriter<filetransfer::UnlockUpload>,
 ) {
-    for _ev in ev_order.read() {
-        ev_pass.send(filetransfer::UnlockUpload);
+    for ev in ev_order.read() {
+        ev_pass.send(filetransfer::UnlockUpload{
+            peer_id: ev.peer_id,
+            data_id: ev.data_id,
+        });
     }
 }
 
@@ -274,6 +492,11 @@ pub struct CreateMapCommand {
     pub y: f32,
     pub map_id: maps::MapId,
     pub data_id: fileload::LoadIdentifier,
+    //Which doors are closed, empty to use the map's own
+    #[serde(default)]
+    pub doors: Vec<bool>,
+    #[serde(default)]
+    pub fog: Option<fog::FogState>,
 }
 
 fn recieve_create_map(
@@ -288,8 +511,12 @@ fn recieve_create_map(
             ev.map_id,
             ev.data_id.clone(),
             Vec3::new(ev.x, 0., ev.y),
+            maps::MapDoors(ev.doors.clone()),
             &mut meshes,
             &mut materials,
+        )).insert((
+            ev.fog.clone().map(fog::MapFog::from).unwrap_or_default(),
+            vision::Explored::default(),
         ));
         ev_load.send(
             fileload::LoadRequest{
@@ -320,3 +547,307 @@ fn recieve_load_encounter(
 }
 
 
+
+#[derive(Event, Serialize, Deserialize, Clone)]
+pub struct AnnounceRoleCommand {
+    pub peer_id: PeerId,
+    pub role: roles::Role,
+    pub name: String,
+}
+
+fn recieve_announce_role(
+    mut ev_order: EventReader<AnnounceRoleCommand>,
+    mut ev_pass: EventWriter<roles::PeerAnnouncedRole>,
+) {
+    for ev in ev_order.read() {
+        ev_pass.send(roles::PeerAnnouncedRole{
+            peer_id: ev.peer_id,
+            role: ev.role,
+            name: ev.name.clone(),
+        });
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Clone)]
+pub struct AssignOwnerCommand {
+    pub id: tokens::TokenId,
+    pub owner: Option<tokens::Owner>,
+}
+
+fn recieve_assign_owner(
+    mut ev_assign_owner: EventReader<AssignOwnerCommand>,
+    mut tokens: Query<(&tokens::TokenId, &mut tokens::TokenOwner)>,
+) {
+    for ev in ev_assign_owner.read() {
+        for (id, mut owner) in tokens.iter_mut() {
+            if *id == ev.id {
+                owner.0 = ev.owner.clone();
+            }
+        }
+    }
+}
+
+//One part of the GM's snapshot, too big to fit in a single packet so it's sent in order over the reliable channel
+#[derive(Event, Serialize, Deserialize, Clone)]
+pub struct SyncEncounterCommand {
+    pub data_id: bank::DataId,
+    pub part: u32,
+    pub parts: u32,
+    pub bytes: Vec<u8>,
+}
+
+fn recieve_sync_encounter(
+    mut ev_order: EventReader<SyncEncounterCommand>,
+    mut snapshot: ResMut<encounters::SnapshotBuffer>,
+    mut ev_pass: EventWriter<encounters::EncounterSync>,
+) {
+    for ev in ev_order.read() {
+        let Some(encounter) = snapshot.add(ev) else {
+            continue;
+        };
+        ev_pass.send(encounters::EncounterSync{
+            data_id: ev.data_id,
+            encounter,
+        });
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Copy, Clone)]
+pub struct ToggleDoorCommand {
+    pub map_id: maps::MapId,
+    pub index: usize,
+    pub closed: bool,
+    //The token reaching for the door, None when the GM does it
+    pub token: Option<tokens::TokenId>,
+}
+
+fn recieve_toggle_door(
+    mut ev_toggle_door: EventReader<ToggleDoorCommand>,
+    mut maps: Query<(&maps::MapId, &maps::MapDimensions, &GlobalTransform, &mut maps::MapDoors)>,
+    tokens: Query<(&tokens::TokenId, &Transform)>,
+    mut event: EventWriter<RequestRedraw>,
+) {
+    for ev in ev_toggle_door.read() {
+        for (id, dimensions, transform, mut doors) in maps.iter_mut() {
+            if *id != ev.map_id {
+                continue;
+            }
+            if let Some(token) = ev.token {
+                let in_reach = tokens.iter()
+                    .find(|(token_id, _)| **token_id == token)
+                    .is_some_and(|(_, token_transform)| {
+                        let position = Vec2::new(token_transform.translation.x, token_transform.translation.z);
+                        doors::in_reach(dimensions, transform, ev.index, position)
+                    });
+                if !in_reach {
+                    continue;
+                }
+            }
+            if let Some(closed) = doors.0.get_mut(ev.index) {
+                *closed = ev.closed;
+                event.send(RequestRedraw);
+            }
+        }
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Copy, Clone)]
+pub struct SetLightingCommand {
+    pub dynamic: bool,
+}
+
+fn recieve_set_lighting(
+    mut ev_set_lighting: EventReader<SetLightingCommand>,
+    mut lighting: ResMut<lighting::Lighting>,
+    mut event: EventWriter<RequestRedraw>,
+) {
+    for ev in ev_set_lighting.read() {
+        lighting.dynamic = ev.dynamic;
+        lighting.chosen = true;
+        event.send(RequestRedraw);
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Clone)]
+pub struct FogCommand {
+    pub edit: fog::FogEdit,
+}
+
+fn recieve_fog(
+    mut ev_fog: EventReader<FogCommand>,
+    mut maps: Query<(&maps::MapDimensions, &GlobalTransform, &mut fog::MapFog)>,
+    mut event: EventWriter<RequestRedraw>,
+) {
+    for ev in ev_fog.read() {
+        fog::apply_edit(&ev.edit, &mut maps);
+        event.send(RequestRedraw);
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Copy, Clone)]
+pub struct DeleteTokenCommand {
+    pub id: tokens::TokenId,
+}
+
+fn recieve_delete_token(
+    mut ev_delete_token: EventReader<DeleteTokenCommand>,
+    mut commands: Commands,
+    tokens: Query<(Entity, &tokens::TokenId)>,
+    mut initiative: ResMut<initiative::Initiative>,
+    mut event: EventWriter<RequestRedraw>,
+) {
+    for ev in ev_delete_token.read() {
+        for (entity, id) in tokens.iter() {
+            if *id == ev.id {
+                commands.entity(entity).despawn_recursive();
+                event.send(RequestRedraw);
+            }
+        }
+        //Every peer takes it out of the turn order itself, so nobody is left waiting on a token that's gone
+        if initiative.entries.iter().any(|entry| entry.id == ev.id) {
+            initiative.remove(ev.id);
+        }
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Copy, Clone)]
+pub struct DuplicateTokenCommand {
+    pub id: tokens::TokenId,
+    //Chosen by the sender so every peer gives the copy the same id
+    pub new_id: tokens::TokenId,
+}
+
+//How far over a copy is put so it doesn't hide the original
+const DUPLICATE_OFFSET: f32 = 5.;
+
+fn recieve_duplicate_token(
+    mut ev_duplicate_token: EventReader<DuplicateTokenCommand>,
+    tokens: Query<(&tokens::TokenId, &fileload::LoadIdentifier, &Transform, &tokens::TokenOwner, &tokens::TokenLock, Option<&health::TokenHealth>, &conditions::TokenConditions)>,
+    mut ev_create_token: EventWriter<CreateTokenCommand>,
+) {
+    for ev in ev_duplicate_token.read() {
+        for (id, load_identifier, transform, owner, lock, health, conditions) in tokens.iter() {
+            if *id == ev.id {
+                ev_create_token.send(CreateTokenCommand{
+                    x: transform.translation.x + DUPLICATE_OFFSET,
+                    y: transform.translation.z,
+                    id: ev.new_id,
+                    load_identifier: load_identifier.clone(),
+                    owned_by: owner.0.clone(),
+                    locked: lock.0,
+                    health: health.copied(),
+                    conditions: conditions.clone(),
+                });
+            }
+        }
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Copy, Clone)]
+pub struct LockTokenCommand {
+    pub id: tokens::TokenId,
+    pub locked: bool,
+}
+
+fn recieve_lock_token(
+    mut ev_lock_token: EventReader<LockTokenCommand>,
+    mut tokens: Query<(&tokens::TokenId, &mut tokens::TokenLock)>,
+) {
+    for ev in ev_lock_token.read() {
+        for (id, mut lock) in tokens.iter_mut() {
+            if *id == ev.id {
+                lock.0 = ev.locked;
+            }
+        }
+    }
+}
+
+//Swap a token on the board over to different data, like after it's been edited
+#[derive(Event, Serialize, Deserialize, Clone)]
+pub struct ChangeTokenDataCommand {
+    pub id: tokens::TokenId,
+    pub load_identifier: fileload::LoadIdentifier,
+}
+
+fn recieve_change_token_data(
+    mut ev_change_token_data: EventReader<ChangeTokenDataCommand>,
+    mut commands: Commands,
+    mut tokens: Query<(Entity, &tokens::TokenId, &mut fileload::LoadIdentifier, Option<&Children>)>,
+    rings: Query<(), With<tokens::TokenRing>>,
+    mut ev_load: EventWriter<fileload::LoadRequest>,
+) {
+    for ev in ev_change_token_data.read() {
+        for (entity, id, mut load_identifier, children) in tokens.iter_mut() {
+            if *id != ev.id {
+                continue;
+            }
+            *load_identifier = ev.load_identifier.clone();
+            //Let load_token build it again from the new data
+            commands.entity(entity).remove::<tokens::TokenLoaded>();
+            for child in children.into_iter().flatten() {
+                if rings.contains(*child) {
+                    commands.entity(*child).despawn_recursive();
+                }
+            }
+            ev_load.send(
+                fileload::LoadRequest{
+                    id: ev.load_identifier.clone(),
+                    endpoint: fileload::FileEndpoint::Token(ev.id),
+                }
+            )
+        }
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Copy, Clone)]
+pub struct SetHealthCommand {
+    pub id: tokens::TokenId,
+    pub health: health::TokenHealth,
+}
+
+fn recieve_set_health(
+    mut ev_set_health: EventReader<SetHealthCommand>,
+    mut commands: Commands,
+    tokens: Query<(Entity, &tokens::TokenId)>,
+) {
+    for ev in ev_set_health.read() {
+        for (entity, id) in tokens.iter() {
+            if *id == ev.id {
+                commands.entity(entity).insert(ev.health);
+            }
+        }
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Clone)]
+pub struct SetInitiativeCommand {
+    pub initiative: initiative::Initiative,
+}
+
+fn recieve_set_initiative(
+    mut ev_set_initiative: EventReader<SetInitiativeCommand>,
+    mut initiative: ResMut<initiative::Initiative>,
+) {
+    for ev in ev_set_initiative.read() {
+        *initiative = ev.initiative.clone();
+    }
+}
+
+#[derive(Event, Serialize, Deserialize, Clone)]
+pub struct SetConditionsCommand {
+    pub id: tokens::TokenId,
+    pub conditions: conditions::TokenConditions,
+}
+
+fn recieve_set_conditions(
+    mut ev_set_conditions: EventReader<SetConditionsCommand>,
+    mut tokens: Query<(&tokens::TokenId, &mut conditions::TokenConditions)>,
+) {
+    for ev in ev_set_conditions.read() {
+        for (id, mut conditions) in tokens.iter_mut() {
+            if *id == ev.id {
+                *conditions = ev.conditions.clone();
+            }
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use bevy::prelude::*;
+use bevy_matchbox::prelude::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+use crate::networking;
+use crate::orders;
+use crate::tokens;
+use crate::ui;
+
+pub struct RolesPlugin;
+
+impl Plugin for RolesPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Roles::new())
+            .add_systems(Update, set_local_role)
+            .add_systems(Update, track_local_id)
+            .add_systems(Update, announce_role_on_connect)
+            .add_systems(Update, forget_disconnected)
+            .add_event::<PeerAnnouncedRole>()
+            .add_systems(Update, recieve_announce_role)
+            .add_systems(Update, report_local_role)
+        ;
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    GameMaster,
+    Player,
+}
+
+//What a peer has to be to have a command applied
+pub enum Authority {
+    Anyone,
+    TokenOwner(tokens::TokenId),
+    GameMaster,
+}
+
+pub struct PeerRole {
+    pub role: Role,
+    pub name: String,
+    //What they asked to join as, kept so it can be given back if whoever beat them to it leaves
+    claimed_role: Role,
+    claimed_name: String,
+    //When we first saw them connect, on our own clock, nothing they say changes it
+    first_seen: f32,
+}
+
+//Peers that connect this soon after we join were already in the room before us
+const JOIN_GRACE: f32 = 5.;
+
+#[derive(Resource)]
+pub struct Roles {
+    pub local: Role,
+    pub local_name: String,
+    claimed_local: Role,
+    claimed_local_name: String,
+    local_joined: f32,
+    pub local_id: Option<PeerId>,
+    pub peers: HashMap<PeerId, PeerRole>,
+    //When each peer connected, kept until they announce who they are
+    connected: HashMap<PeerId, f32>,
+}
+
+impl Roles {
+    pub fn new() -> Roles {
+        Roles {
+            local: Role::Player,
+            local_name: "".to_string(),
+            claimed_local: Role::Player,
+            claimed_local_name: "".to_string(),
+            local_joined: 0.,
+            local_id: None,
+            peers: HashMap::new(),
+            connected: HashMap::new(),
+        }
+    }
+
+    pub fn is_game_master(&self) -> bool {
+        self.local == Role::GameMaster
+    }
+
+    pub fn game_master(&self) -> Option<PeerId> {
+        self.peers.iter()
+            .find(|(_, peer)| peer.role == Role::GameMaster)
+            .map(|(id, _)| *id)
+    }
+
+    pub fn peer_name(&self, peer_id: &PeerId) -> Option<&String> {
+        self.peers.get(peer_id).map(|peer| &peer.name)
+    }
+
+    //Called as soon as the announcement arrives so the peer's following commands are checked against it
+    pub fn register(&mut self, peer_id: PeerId, role: Role, name: String, now: f32) {
+        let first_seen = self.connected.get(&peer_id).copied().unwrap_or(now);
+        let peer = self.peers.entry(peer_id).or_insert(PeerRole{
+            //Nobody is GM until resolve says so
+            role: Role::Player,
+            name: name.clone(),
+            claimed_role: role,
+            claimed_name: name.clone(),
+            first_seen,
+        });
+        peer.claimed_role = role;
+        peer.claimed_name = name;
+        self.resolve(now);
+    }
+
+    fn forget(&mut self, peer_id: &PeerId, now: f32) {
+        self.peers.remove(peer_id);
+        self.connected.remove(peer_id);
+        self.resolve(now);
+    }
+
+    //Only one game master per room and no two people with the same name, whoever we saw first keeps them
+    //A GM who's already settled in stays GM until they leave, however anyone else announces
+    fn resolve(&mut self, now: f32) {
+        //Our own claim isn't settled until we've had time to hear from whoever was already here
+        let settled = now - self.local_joined > JOIN_GRACE;
+        let incumbent = self.peers.iter()
+            .find(|(_, peer)| peer.role == Role::GameMaster && peer.claimed_role == Role::GameMaster)
+            .map(|(id, _)| Some(*id))
+            .or((self.local == Role::GameMaster && settled).then_some(None));
+
+        //None is us, peers that were here before we joined come first
+        let local_joined = self.local_joined;
+        let mut everyone: Vec<(Option<PeerId>, u8, f32)> = self.peers.iter()
+            .map(|(id, peer)| {
+                let before_us = peer.first_seen <= local_joined + JOIN_GRACE;
+                (Some(*id), if before_us { 0 } else { 2 }, peer.first_seen)
+            })
+            .collect();
+        everyone.push((None, 1, local_joined));
+        everyone.sort_by(|a, b| a.1.cmp(&b.1)
+            .then(a.2.total_cmp(&b.2))
+            .then_with(|| a.0.map(|id| id.to_string()).cmp(&b.0.map(|id| id.to_string()))));
+
+        let mut has_gm = incumbent.is_some();
+        let mut taken = HashSet::new();
+        for (id, _, _) in everyone {
+            let (claimed_role, claimed_name) = match id {
+                Some(id) => (self.peers[&id].claimed_role, self.peers[&id].claimed_name.clone()),
+                None => (self.claimed_local, self.claimed_local_name.clone()),
+            };
+            let role = if incumbent == Some(id) {
+                Role::GameMaster
+            } else if claimed_role == Role::GameMaster && !has_gm {
+                has_gm = true;
+                Role::GameMaster
+            } else {
+                Role::Player
+            };
+            let mut name = claimed_name.clone();
+            let mut count = 2;
+            while !taken.insert(name.clone()) {
+                name = format!("{claimed_name} ({count})");
+                count += 1;
+            }
+            match id {
+                Some(id) => {
+                    let peer = self.peers.get_mut(&id).unwrap();
+                    peer.role = role;
+                    peer.name = name;
+                },
+                None => {
+                    self.local = role;
+                    self.local_name = name;
+                },
+            }
+        }
+    }
+
+    pub fn is_local_owner(&self, owner: &tokens::TokenOwner) -> bool {
+        self.local_id.is_some_and(|id| owner.is_owned_by(&id))
+    }
+
+    //Can the local user issue this command
+    pub fn can_local(&self, owner: Option<&tokens::TokenOwner>) -> bool {
+        if self.is_game_master() {
+            return true;
+        }
+        owner.is_some_and(|owner| self.is_local_owner(owner))
+    }
+
+    //Should a command recieved from a remote peer be applied
+    pub fn is_authorized(
+        &self,
+        peer_id: &PeerId,
+        command: &orders::Command,
+        owners: &Query<(&tokens::TokenId, &tokens::TokenOwner)>,
+    ) -> bool {
+        let Some(peer) = self.peers.get(peer_id) else {
+            //Peers that haven't said who they are can only do the basics
+            return matches!(command.authority(), Authority::Anyone);
+        };
+        if peer.role == Role::GameMaster {
+            return true;
+        }
+        match command.authority() {
+            Authority::Anyone => true,
+            Authority::GameMaster => false,
+            Authority::TokenOwner(id) => owners.iter()
+                .any(|(token_id, owner)| *token_id == id && owner.is_owned_by(peer_id)),
+        }
+    }
+}
+
+fn set_local_role(
+    mut roles: ResMut<Roles>,
+    mut ev_join: EventReader<networking::JoinRoom>,
+    time: Res<Time>,
+) {
+    for ev in ev_join.read() {
+        roles.claimed_local = if ev.settings.game_master {
+            Role::GameMaster
+        } else {
+            Role::Player
+        };
+        roles.claimed_local_name = ev.settings.name.trim().to_string();
+        roles.local_joined = time.elapsed_seconds();
+        roles.peers.clear();
+        roles.connected.clear();
+        roles.resolve(time.elapsed_seconds());
+    }
+}
+
+fn track_local_id(
+    mut roles: ResMut<Roles>,
+    local_peer_id: Option<Res<networking::LocalPeerId>>,
+) {
+    let id = local_peer_id.map(|local| local.id);
+    if roles.local_id != id {
+        roles.local_id = id;
+    }
+}
+
+fn announce_role_on_connect(
+    mut roles: ResMut<Roles>,
+    mut ev_connected: EventReader<networking::PeerConnected>,
+    mut ev_networked: EventWriter<networking::NetworkedCommandEvent>,
+    local_peer_id: Option<Res<networking::LocalPeerId>>,
+    time: Res<Time>,
+    mut last_announced: Local<Option<Role>>,
+) {
+    let Some(local_peer_id) = local_peer_id else {
+        return
+    };
+    let mut recipients = Vec::new();
+    for ev in ev_connected.read() {
+        roles.connected.insert(ev.0, time.elapsed_seconds());
+        recipients.push(networking::RecepientPeer::Peer(ev.0));
+    }
+    //Let everyone know when we've taken over or handed back the GM role, so a demoted claimant isn't mistaken for the GM
+    if last_announced.is_some_and(|role| role != roles.local) {
+        recipients = vec![networking::RecepientPeer::All];
+    }
+    if recipients.is_empty() {
+        return;
+    }
+    *last_announced = Some(roles.local);
+    for peer_id in recipients {
+        //The role we actually have and the name we asked for, the other peer decides for itself what we get
+        ev_networked.send(networking::NetworkedCommandEvent{
+            order: orders::OrderEvent{
+                command: orders::Command::AnnounceRole(orders::AnnounceRoleCommand{
+                    peer_id: local_peer_id.id,
+                    role: roles.local,
+                    name: roles.claimed_local_name.clone(),
+                }),
+            },
+            reliability: networking::NetworkReliability::Reliable,
+            peer_id,
+        });
+    }
+}
+
+fn forget_disconnected(
+    mut roles: ResMut<Roles>,
+    mut ev_disconnected: EventReader<networking::PeerDisconnected>,
+    time: Res<Time>,
+) {
+    for ev in ev_disconnected.read() {
+        roles.forget(&ev.0, time.elapsed_seconds());
+    }
+}
+
+#[derive(Event)]
+pub struct PeerAnnouncedRole {
+    pub peer_id: PeerId,
+    pub role: Role,
+    pub name: String,
+}
+
+fn recieve_announce_role(
+    roles: Res<Roles>,
+    mut ev_announce: EventReader<PeerAnnouncedRole>,
+    mut events: EventWriter<ui::InsertLog>,
+) {
+    for ev in ev_announce.read() {
+        let Some(peer) = roles.peers.get(&ev.peer_id) else {
+            continue;
+        };
+        if ev.role != peer.role {
+            events.send(ui::InsertLog::new(format!("{} claimed GM, but the room already has one", ev.name)));
+        }
+        let role_name = match peer.role {
+            Role::GameMaster => "Game Master",
+            Role::Player => "Player",
+        };
+        events.send(ui::InsertLog::new(format!("{} joined as {}", peer.name, role_name)));
+    }
+}
+
+//Let the local user know when someone who was here first already had their role or name
+fn report_local_role(
+    roles: Res<Roles>,
+    mut last: Local<Option<(Role, String)>>,
+    mut events: EventWriter<ui::InsertLog>,
+) {
+    if !roles.is_changed() {
+        return;
+    }
+    let current = (roles.local, roles.local_name.clone());
+    if last.as_ref() == Some(&current) {
+        return;
+    }
+    if roles.claimed_local == Role::GameMaster && roles.local != Role::GameMaster {
+        events.send(ui::InsertLog::new("The room already has a Game Master, you've joined as a Player".to_string()));
+    }
+    if roles.local_name != roles.claimed_local_name {
+        events.send(ui::InsertLog::new(format!("Your name was already taken, you're playing as {}", roles.local_name)));
+    }
+    *last = Some(current);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u128) -> PeerId {
+        PeerId(uuid::Uuid::from_u128(n))
+    }
+
+    fn roles(local_role: Role, local_name: &str) -> Roles {
+        let mut roles = Roles::new();
+        roles.claimed_local = local_role;
+        roles.claimed_local_name = local_name.to_string();
+        roles.resolve(0.);
+        roles
+    }
+
+    //A peer that connects at `at` and announces straight away
+    fn join(roles: &mut Roles, id: PeerId, role: Role, name: &str, at: f32) {
+        roles.connected.insert(id, at);
+        roles.register(id, role, name.to_string(), at);
+    }
+
+    #[test]
+    fn first_gm_claimant_keeps_it() {
+        let mut roles = roles(Role::Player, "Local");
+        join(&mut roles, peer(1), Role::GameMaster, "First", 10.);
+        join(&mut roles, peer(2), Role::GameMaster, "Second", 20.);
+        assert_eq!(roles.game_master(), Some(peer(1)));
+        assert_eq!(roles.peers[&peer(2)].role, Role::Player);
+    }
+
+    #[test]
+    fn settled_gm_isnt_displaced() {
+        let mut roles = roles(Role::GameMaster, "Local");
+        join(&mut roles, peer(1), Role::GameMaster, "Late", JOIN_GRACE + 10.);
+        assert!(roles.is_game_master());
+        assert_eq!(roles.peers[&peer(1)].role, Role::Player);
+    }
+
+    #[test]
+    fn reannouncing_doesnt_take_gm() {
+        let mut roles = roles(Role::Player, "Local");
+        join(&mut roles, peer(1), Role::GameMaster, "GM", 10.);
+        join(&mut roles, peer(2), Role::Player, "Player", 20.);
+        roles.register(peer(2), Role::GameMaster, "Player".to_string(), 30.);
+        assert_eq!(roles.game_master(), Some(peer(1)));
+    }
+
+    #[test]
+    fn peers_already_here_come_before_us() {
+        let mut roles = roles(Role::GameMaster, "Local");
+        join(&mut roles, peer(1), Role::GameMaster, "Host", 1.);
+        assert!(!roles.is_game_master());
+        assert_eq!(roles.game_master(), Some(peer(1)));
+    }
+
+    #[test]
+    fn gm_leaving_hands_it_on() {
+        let mut roles = roles(Role::Player, "Local");
+        join(&mut roles, peer(1), Role::GameMaster, "First", 10.);
+        join(&mut roles, peer(2), Role::GameMaster, "Second", 20.);
+        roles.forget(&peer(1), 30.);
+        assert_eq!(roles.game_master(), Some(peer(2)));
+    }
+
+    #[test]
+    fn duplicate_names_are_numbered() {
+        let mut roles = roles(Role::Player, "Sam");
+        join(&mut roles, peer(1), Role::Player, "Sam", 10.);
+        join(&mut roles, peer(2), Role::Player, "Sam", 20.);
+        assert_eq!(roles.local_name, "Sam");
+        assert_eq!(roles.peers[&peer(1)].name, "Sam (2)");
+        assert_eq!(roles.peers[&peer(2)].name, "Sam (3)");
+    }
+}
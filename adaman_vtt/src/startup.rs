@@ -1,5 +1,7 @@
 use bevy::prelude::*;
 
+use crate::lighting;
+
 pub struct GameStartPlugin;
 
 impl Plugin for GameStartPlugin {
@@ -19,14 +21,17 @@ fn setup(mut commands: Commands) {
     );
     commands.spawn(camera_bundle);
 
-    commands.spawn(PointLightBundle {
-        point_light: PointLight {
-            intensity: 9000.0,
-            range: 500.,
-            shadows_enabled: true,
+    commands.spawn((
+        PointLightBundle {
+            point_light: PointLight {
+                intensity: 9000.0,
+                range: 500.,
+                shadows_enabled: true,
+                ..default()
+            },
+            transform: Transform::from_xyz(8.0, 16.0, 8.0),
             ..default()
         },
-        transform: Transform::from_xyz(8.0, 16.0, 8.0),
-        ..default()
-    });
+        lighting::DefaultLight,
+    ));
 }
@@ -2,10 +2,14 @@ use crate::input;
 use crate::fileload;
 use bevy::prelude::*;
 use bevy_mod_picking::prelude::*;
+use bevy_matchbox::prelude::PeerId;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 use std::sync::Arc;
+use std::io::Cursor;
+use image::io::Reader as ImageReader;
+use base64::{Engine as _, engine::general_purpose};
 
 pub struct TokenPlugin;
 
@@ -32,16 +36,45 @@ pub struct TokenBundle {
     pub pickable: PickableBundle,
     #[bundle()]
     pub drag_event: On<Pointer<Drag>>,
+    #[bundle()]
+    pub drag_end_event: On<Pointer<DragEnd>>,
+    #[bundle()]
+    pub click_event: On<Pointer<Click>>,
     pub token: TokenFlag,
+    pub owner: TokenOwner,
+    pub lock: TokenLock,
 }
 
 #[derive(Component)]
 pub struct TokenFlag;
 
+//The player allowed to move this token, the GM can always move it
+#[derive(Component, Clone, Default)]
+pub struct TokenOwner(pub Option<Owner>);
+
+//Keyed on the peer id the signalling server gave them, which they can't pick, the name is only for showing who it is
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Owner {
+    pub peer_id: PeerId,
+    pub name: String,
+}
+
+//Locked tokens can't be dragged by anyone until they're unlocked
+#[derive(Component, Clone, Copy, Default)]
+pub struct TokenLock(pub bool);
+
+impl TokenOwner {
+    pub fn is_owned_by(&self, peer_id: &PeerId) -> bool {
+        self.0.as_ref().is_some_and(|owner| owner.peer_id == *peer_id)
+    }
+}
+
 impl TokenBundle {
     pub fn new(
         id: TokenId,
         load_identifier: fileload::LoadIdentifier,
+        owner: TokenOwner,
+        lock: TokenLock,
         position: Vec3,
         meshes: &mut ResMut<Assets<Mesh>>,
         materials: &mut ResMut<Assets<StandardMaterial>>,
@@ -67,13 +100,17 @@ impl TokenBundle {
             },
             pickable: PickableBundle::default(), // Makes the entity pickable
             drag_event: On::<Pointer<Drag>>::send_event::<input::TokenDragEvent>(),
+            drag_end_event: On::<Pointer<DragEnd>>::send_event::<input::TokenDragEndEvent>(),
+            click_event: On::<Pointer<Click>>::send_event::<input::TokenClickEvent>(),
             token: TokenFlag,
+            owner,
+            lock,
             load_identifier,
         }
     }
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Default)]
 pub struct TokenData {
     pub format: f32,
     pub name: String,
@@ -82,6 +119,78 @@ pub struct TokenData {
     pub hit_points: i64,
     pub armor_class: i64,
     pub img: Option<String>,
+    #[serde(default)]
+    pub senses: String,
+    #[serde(default)]
+    pub stats: TokenStats,
+    //The portrait itself, so it travels with the token instead of every peer fetching img
+    #[serde(default)]
+    image_str: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct TokenStats {
+    pub strength: i64,
+    pub dexterity: i64,
+    pub constitution: i64,
+    pub intelligence: i64,
+    pub wisdom: i64,
+    pub charisma: i64,
+}
+
+impl Default for TokenStats {
+    fn default() -> Self {
+        TokenStats {
+            strength: 10,
+            dexterity: 10,
+            constitution: 10,
+            intelligence: 10,
+            wisdom: 10,
+            charisma: 10,
+        }
+    }
+}
+
+impl TokenStats {
+    pub fn modifier(score: i64) -> i64 {
+        (score - 10).div_euclid(2)
+    }
+}
+
+//Every size a token can be, smallest first
+pub const SIZES: [&str; 6] = ["Tiny", "Small", "Medium", "Large", "Huge", "Gargantuan"];
+
+//Portraits are shrunk to this before they're stored, they're only ever drawn small
+const PORTRAIT_SIZE: u32 = 256;
+
+impl TokenData {
+    pub fn get_image(&self) -> Option<Vec<u8>> {
+        general_purpose::STANDARD.decode(self.image_str.as_ref()?).ok()
+    }
+
+    pub fn has_image(&self) -> bool {
+        self.image_str.is_some()
+    }
+
+    //Crops the image to a square around its middle and shrinks it, returns false if it isn't an image
+    pub fn set_image(&mut self, raw: &[u8]) -> bool {
+        let Some(image_data) = ImageReader::new(Cursor::new(raw))
+            .with_guessed_format()
+            .ok()
+            .and_then(|reader| reader.decode().ok()) else {
+            return false;
+        };
+        let side = image_data.width().min(image_data.height());
+        let portrait = image_data
+            .crop_imm((image_data.width() - side) / 2, (image_data.height() - side) / 2, side, side)
+            .resize(PORTRAIT_SIZE, PORTRAIT_SIZE, image::imageops::FilterType::Triangle);
+        let mut encoded = Cursor::new(Vec::new());
+        if portrait.write_to(&mut encoded, image::ImageOutputFormat::Png).is_err() {
+            return false;
+        }
+        self.image_str = Some(general_purpose::STANDARD.encode(encoded.into_inner()));
+        true
+    }
 }
 
 #[derive(Component)]
@@ -91,6 +200,8 @@ pub struct StrippedTokenData {
     pub type_field: String,
     pub hit_points: i64,
     pub armor_class: i64,
+    pub senses: String,
+    pub stats: TokenStats,
 }
 
 impl From<TokenData> for StrippedTokenData {
@@ -101,10 +212,15 @@ impl From<TokenData> for StrippedTokenData {
             type_field: x.type_field,
             hit_points: x.hit_points,
             armor_class: x.armor_class,
+            senses: x.senses,
+            stats: x.stats,
         }
     }
 }
 
+//How far a token sees when its senses don't say, in feet
+pub const DEFAULT_VISION_RANGE: f32 = 60.;
+
 impl StrippedTokenData {
     #[allow(clippy::if_same_then_else)]
     pub fn get_radius(&self) -> f32 {
@@ -130,6 +246,18 @@ impl StrippedTokenData {
             2.5
         }
     }
+
+    //The longest of the token's senses that let it see, eg "darkvision 60 ft., passive Perception 10", tremorsense doesn't count
+    //Tokens without any listed see out to the default instead
+    pub fn get_vision_range(&self) -> f32 {
+        let senses = self.senses.to_lowercase();
+        let words: Vec<&str> = senses.split(|c: char| !c.is_alphanumeric()).filter(|x| !x.is_empty()).collect();
+        words.windows(2)
+            .filter(|pair| ["darkvision", "blindsight", "truesight"].contains(&pair[0]))
+            .filter_map(|pair| pair[1].parse::<f32>().ok())
+            .reduce(f32::max)
+            .unwrap_or(DEFAULT_VISION_RANGE)
+    }
 }
 
 impl TokenData {
@@ -168,6 +296,10 @@ pub struct TokenLoad {
 #[derive(Serialize, Deserialize, Clone, Copy, Component, Eq, Hash, PartialEq)]
 pub struct TokenLoaded;
 
+//The colored ring around a token, rebuilt whenever the token's data changes
+#[derive(Component)]
+pub struct TokenRing;
+
 pub fn load_token(
     mut commands: Commands,
     mut ev_token_load: EventReader<TokenLoad>,
@@ -182,17 +314,15 @@ pub fn load_token(
             println!("Bad Token Data");
             continue;
         };
-        //Deserialize the image data
-        //let image_data = ImageReader::new(
-            //Cursor::new(data.get_image().clone()))
-            //.with_guessed_format()
-            //.expect("Unable to guess format")
-            //.decode()
-            //.expect("Malformed Image");
-        //Get the image in bevy's format
-        //let bevy_image = Image::from_dynamic(image_data, true);
-        //Insert it into the images pool
-        //let image_handle = images.add(bevy_image);
+        //Deserialize the portrait, if it has one
+        let image_handle = data.get_image()
+            .and_then(|image| ImageReader::new(Cursor::new(image))
+                .with_guessed_format()
+                .ok()?
+                .decode()
+                .ok())
+            //Get the image in bevy's format and insert it into the images pool
+            .map(|image_data| images.add(Image::from_dynamic(image_data, true)));
 
         for token in tokens.iter_mut() {
             //Check if the id matches
@@ -205,7 +335,7 @@ pub fn load_token(
                     continue;
                 };
                 //Replace the material's image with the new one
-                //mat.base_color_texture = Some(image_handle.clone());
+                mat.base_color_texture = image_handle.clone();
 
                 let radius = data.get_radius();
                 println!("{radius}");
@@ -221,7 +351,7 @@ pub fn load_token(
                     vertices: 64,
                 };
 
-                let ring = commands.spawn(
+                let ring = commands.spawn((
                     PbrBundle {
                         mesh: meshes.add(ring.into()),
                         material: materials.add(StandardMaterial {
@@ -231,7 +361,9 @@ pub fn load_token(
                         transform: Transform::from_xyz(0., 0., -0.4)
                             .with_rotation(Quat::from_euler(EulerRot::XYZ, 0., 0., std::f32::consts::PI)),
                         ..default()
-                }).id();
+                    },
+                    TokenRing,
+                )).id();
 
                 commands.entity(token.2).push_children(&[ring]);
 
@@ -252,3 +384,17 @@ fn generate_token_color(id: &TokenId) -> Color {
         (((val + 12181176441617899549) % 83443272550043138497) % 255) as f32 / 255.,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ability_modifiers() {
+        assert_eq!(TokenStats::modifier(10), 0);
+        assert_eq!(TokenStats::modifier(11), 0);
+        assert_eq!(TokenStats::modifier(9), -1);
+        assert_eq!(TokenStats::modifier(1), -5);
+        assert_eq!(TokenStats::modifier(20), 5);
+    }
+}
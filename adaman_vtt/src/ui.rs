@@ -3,11 +3,23 @@ use serde::{Serialize, Deserialize};
 use bevy_egui::{egui, EguiContexts, EguiPlugin};
 
 use crate::input;
+use crate::maps;
+use crate::grid;
+use crate::vision;
+use crate::lighting;
+use crate::fog;
+use crate::health;
+use crate::initiative;
+use crate::conditions;
 use crate::networking;
 use crate::files;
 use crate::bank;
 use crate::encounters;
 use crate::open5e;
+use crate::join;
+use crate::roles;
+use crate::tokens;
+use crate::fileload;
 
 use std::collections::VecDeque;
 
@@ -28,6 +40,14 @@ impl Plugin for UIPlugin {
             .insert_resource(TextMessages{messages: Vec::new().into(), input: "".to_string()})
             .add_event::<RecieveMessage>()
             .add_systems(Update, update_messages.before(text_messages))
+            .add_systems(Update, connection_status.after(ui))
+            .add_systems(Update, grid_settings.after(ui))
+            .add_systems(Update, token_editor.after(ui))
+            .add_systems(Update, token_context_menu.after(ui))
+            .add_systems(Update, hit_points.after(ui))
+            .add_systems(Update, initiative_tracker.after(ui))
+            .add_systems(Update, token_conditions.after(ui))
+            .add_systems(Update, map_calibration.after(ui).run_if(resource_exists::<input::MapCalibration>()))
         ;
     }
 }
@@ -98,6 +118,7 @@ fn update_ui_state(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn ui(
     mut contexts: EguiContexts,
     mut ui_state: ResMut<UIState>,
@@ -105,16 +126,67 @@ fn ui(
     ev_save_encounter: EventWriter<encounters::EncounterSave>,
     mut ev_create_map: EventWriter<input::CreateMapFromFile>,
     mut ev_create_token: EventWriter<input::CreateTokenFromData>,
+    mut ev_upload_portrait: EventWriter<input::UploadTokenPortrait>,
+    mut ev_open_editor: EventWriter<input::OpenTokenEditor>,
     mut connection: ResMut<open5e::Open5eMonsterSelection>,
+    roles: Res<roles::Roles>,
+    board_tokens: Query<(&tokens::TokenId, &tokens::TokenOwner, Option<&tokens::StrippedTokenData>)>,
+    mut vision_settings: ResMut<vision::VisionSettings>,
+    lighting: Res<lighting::Lighting>,
+    mut fog_tool: ResMut<fog::FogTool>,
+    fogs: Query<&fog::MapFog>,
 ) {
     egui::SidePanel::right("Token Creation")
         .min_width(200.0)
         .max_width(300.0)
         .show(contexts.ctx_mut(), |ui| {
+            if !roles.is_game_master() {
+                ui.label(format!("Playing as {}", roles.local_name));
+                ui.separator();
+                ui.label("Only the Game Master can add maps, tokens and encounters.");
+                return;
+            }
+            ui.label("Game Master");
+            ui.separator();
             match ui_state.right_sidepanel_state {
                 SidePanelState::Maps => {
                     let create_map_file_btn = ui.button("Import Map");
                     ui.text_edit_singleline(&mut ui_state.map_name);
+                    ui.checkbox(&mut vision_settings.show_walls, "Show Walls");
+                    let mut dynamic = lighting.dynamic;
+                    if ui.checkbox(&mut dynamic, "Dynamic Lighting").changed() {
+                        input::set_lighting(dynamic, &mut ev_client);
+                    }
+                    ui.collapsing("Fog of War", |ui| {
+                        let mut enabled = fogs.iter().any(|fog| fog.enabled);
+                        if ui.checkbox(&mut enabled, "Enabled").changed() {
+                            fog::send_fog_edit(fog::FogEdit::Enable(enabled), &mut ev_client);
+                        }
+                        ui.horizontal(|ui| {
+                            ui.selectable_value(&mut fog_tool.reveal, true, "Reveal");
+                            ui.selectable_value(&mut fog_tool.reveal, false, "Hide");
+                        });
+                        ui.horizontal(|ui| {
+                            ui.selectable_value(&mut fog_tool.shape, fog::FogToolShape::None, "Off");
+                            ui.selectable_value(&mut fog_tool.shape, fog::FogToolShape::Rectangle, "Rect");
+                            ui.selectable_value(&mut fog_tool.shape, fog::FogToolShape::Polygon, "Polygon");
+                            ui.selectable_value(&mut fog_tool.shape, fog::FogToolShape::Brush, "Brush");
+                        });
+                        if fog_tool.shape == fog::FogToolShape::Brush {
+                            ui.add(egui::Slider::new(&mut fog_tool.brush_radius, 1.0..=30.0).text("Radius"));
+                        }
+                        if fog_tool.shape == fog::FogToolShape::Polygon {
+                            ui.label("Click to add points, right click to finish");
+                        }
+                        ui.horizontal(|ui| {
+                            if ui.button("Reveal All").clicked() {
+                                fog::send_fog_edit(fog::FogEdit::All{ reveal: true }, &mut ev_client);
+                            }
+                            if ui.button("Hide All").clicked() {
+                                fog::send_fog_edit(fog::FogEdit::All{ reveal: false }, &mut ev_client);
+                            }
+                        });
+                    });
                     if create_map_file_btn.clicked() {
                         ev_create_map.send(
                             input::CreateMapFromFile {
@@ -141,15 +213,67 @@ fn ui(
                     if create_token_btn.clicked() {
                         ui_state.popup_panel_state = PopupState::TokenCreation;
                     }
+                    let new_token_btn = ui.button("Create By Hand");
+                    if new_token_btn.clicked() {
+                        ev_open_editor.send(input::OpenTokenEditor{
+                            load_identifier: None,
+                            board_token: None,
+                        });
+                    }
+                    ui.collapsing("Owners", |ui| {
+                        for (id, owner, data) in board_tokens.iter() {
+                            let name = data.map(|x| x.name.clone()).unwrap_or("Loading".to_string());
+                            let mut selected = owner.0.clone();
+                            //Owners from an earlier session have a different peer id now and need giving the token again
+                            let selected_text = match &selected {
+                                Some(owner) if roles.peers.contains_key(&owner.peer_id) => owner.name.clone(),
+                                Some(owner) => format!("{} (away)", owner.name),
+                                None => "GM".to_string(),
+                            };
+                            ui.horizontal(|ui| {
+                                ui.label(name);
+                                egui::ComboBox::from_id_source(id.0)
+                                    .selected_text(selected_text)
+                                    .show_ui(ui, |ui| {
+                                        ui.selectable_value(&mut selected, None, "GM");
+                                        for (peer_id, peer) in roles.peers.iter() {
+                                            let owner = tokens::Owner{
+                                                peer_id: *peer_id,
+                                                name: peer.name.clone(),
+                                            };
+                                            ui.selectable_value(&mut selected, Some(owner), &peer.name);
+                                        }
+                                    });
+                            });
+                            if selected != owner.0 {
+                                input::assign_owner(*id, selected, &mut ev_client);
+                            }
+                        }
+                    });
                     if let Some(ref token_list) = &ui_state.token_list {
                         egui::ScrollArea::vertical().show(ui, |ui| {
                             for token in token_list.tokens.iter() {
                                 ui.separator();
                                 ui.label(token.name.clone());
-                                let insert_btn = ui.button("Load");
-                                if insert_btn.clicked() {
-                                    input::create_token(token.load_identifier.clone(), &mut ev_client);
-                                }
+                                ui.horizontal(|ui| {
+                                    let insert_btn = ui.button("Load");
+                                    if insert_btn.clicked() {
+                                        input::create_token(token.load_identifier.clone(), &mut ev_client);
+                                    }
+                                    let portrait_btn = ui.button("Set Portrait");
+                                    if portrait_btn.clicked() {
+                                        ev_upload_portrait.send(input::UploadTokenPortrait{
+                                            target: input::PortraitTarget::Library(token.load_identifier.clone()),
+                                        });
+                                    }
+                                    let edit_btn = ui.button("Edit");
+                                    if edit_btn.clicked() {
+                                        ev_open_editor.send(input::OpenTokenEditor{
+                                            load_identifier: Some(token.load_identifier.clone()),
+                                            board_token: None,
+                                        });
+                                    }
+                                });
                             }
                         });
                     }
@@ -224,6 +348,499 @@ fn ui(
     }
 }
 
+fn token_editor(
+    mut contexts: EguiContexts,
+    mut editor: ResMut<input::TokenEditor>,
+    mut ev_upload_portrait: EventWriter<input::UploadTokenPortrait>,
+    mut ev_save: EventWriter<input::SaveTokenData>,
+) {
+    if !editor.open {
+        return;
+    }
+    let mut open = true;
+    let title = if editor.editing.is_some() { "Edit Token" } else { "New Token" };
+    egui::Window::new(title)
+        .open(&mut open)
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(contexts.ctx_mut(), |ui| {
+            let data = &mut editor.data;
+            egui::Grid::new("token_editor_grid").num_columns(2).show(ui, |ui| {
+                ui.label("Name");
+                ui.text_edit_singleline(&mut data.name);
+                ui.end_row();
+                ui.label("Size");
+                egui::ComboBox::from_id_source("token_editor_size")
+                    .selected_text(data.size.clone())
+                    .show_ui(ui, |ui| {
+                        for size in tokens::SIZES {
+                            ui.selectable_value(&mut data.size, size.to_string(), size);
+                        }
+                    });
+                ui.end_row();
+                ui.label("Type");
+                ui.text_edit_singleline(&mut data.type_field);
+                ui.end_row();
+                ui.label("HP");
+                ui.add(egui::DragValue::new(&mut data.hit_points).clamp_range(0..=9999));
+                ui.end_row();
+                ui.label("AC");
+                ui.add(egui::DragValue::new(&mut data.armor_class).clamp_range(0..=99));
+                ui.end_row();
+                ui.label("Senses");
+                ui.text_edit_singleline(&mut data.senses);
+                ui.end_row();
+                let stats = &mut data.stats;
+                for (label, score) in [
+                    ("STR", &mut stats.strength),
+                    ("DEX", &mut stats.dexterity),
+                    ("CON", &mut stats.constitution),
+                    ("INT", &mut stats.intelligence),
+                    ("WIS", &mut stats.wisdom),
+                    ("CHA", &mut stats.charisma),
+                ] {
+                    ui.label(label);
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(score).clamp_range(1..=30));
+                        ui.label(format!("{:+}", tokens::TokenStats::modifier(*score)));
+                    });
+                    ui.end_row();
+                }
+                ui.label("Image");
+                ui.horizontal(|ui| {
+                    ui.label(if data.has_image() { "Set" } else { "None" });
+                    if ui.button("Choose").clicked() {
+                        ev_upload_portrait.send(input::UploadTokenPortrait{
+                            target: input::PortraitTarget::Editor,
+                        });
+                    }
+                });
+                ui.end_row();
+            });
+            let valid = !editor.data.name.trim().is_empty();
+            if ui.add_enabled(valid, egui::Button::new("Save")).clicked() {
+                ev_save.send(input::SaveTokenData);
+            }
+        });
+    if !open {
+        editor.open = false;
+    }
+}
+
+fn token_context_menu(
+    mut contexts: EguiContexts,
+    mut menu: ResMut<input::TokenMenu>,
+    tokens: Query<(&tokens::TokenId, &fileload::LoadIdentifier, &tokens::TokenLock, Option<&tokens::StrippedTokenData>)>,
+    mut ev_open_editor: EventWriter<input::OpenTokenEditor>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+) {
+    let Some(id) = menu.token else {
+        return;
+    };
+    //The token might have been deleted by someone else while the menu was up
+    let Some((_, load_identifier, lock, data)) = tokens.iter().find(|(token_id, ..)| **token_id == id) else {
+        menu.token = None;
+        return;
+    };
+    let mut close = false;
+    let area = egui::Area::new("token_context_menu")
+        .fixed_pos(egui::pos2(menu.position.x, menu.position.y))
+        .order(egui::Order::Foreground)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Frame::menu(ui.style()).show(ui, |ui| {
+                ui.label(data.map(|x| x.name.clone()).unwrap_or("Loading".to_string()));
+                ui.separator();
+                if ui.button("Edit").clicked() {
+                    ev_open_editor.send(input::OpenTokenEditor{
+                        load_identifier: Some(load_identifier.clone()),
+                        board_token: Some(id),
+                    });
+                    close = true;
+                }
+                if ui.button("Duplicate").clicked() {
+                    input::duplicate_token(id, &mut ev_client);
+                    close = true;
+                }
+                let lock_label = if lock.0 { "Unlock Position" } else { "Lock Position" };
+                if ui.button(lock_label).clicked() {
+                    input::lock_token(id, !lock.0, &mut ev_client);
+                    close = true;
+                }
+                if ui.button("Delete").clicked() {
+                    input::delete_token(id, &mut ev_client);
+                    close = true;
+                }
+            });
+        });
+    //Clicking anywhere else dismisses the menu
+    let ctx = contexts.ctx_mut();
+    let over_menu = ctx.pointer_interact_pos().is_some_and(|pos| area.response.rect.contains(pos));
+    if ctx.input(|i| i.pointer.any_pressed()) && !over_menu {
+        close = true;
+    }
+    if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
+        close = true;
+    }
+    if close {
+        menu.token = None;
+    }
+}
+
+fn hit_points(
+    mut contexts: EguiContexts,
+    roles: Res<roles::Roles>,
+    tokens: Query<(&tokens::TokenId, &tokens::TokenOwner, &tokens::StrippedTokenData, &health::TokenHealth)>,
+    mut amount: Local<i64>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+) {
+    egui::Window::new("Hit Points")
+        .default_open(false)
+        .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-5., -5.))
+        .show(contexts.ctx_mut(), |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Amount");
+                ui.add(egui::DragValue::new(&mut *amount).clamp_range(0..=9999));
+            });
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                //Only the tokens we're allowed to change
+                for (id, owner, data, health) in tokens.iter().filter(|(_, owner, ..)| roles.can_local(Some(owner))) {
+                    ui.separator();
+                    let temp = if health.temp > 0 { format!(" +{}", health.temp) } else { String::new() };
+                    ui.label(format!("{}: {}/{}{}", data.name, health.current, data.hit_points, temp));
+                    let mut new_health = *health;
+                    ui.horizontal(|ui| {
+                        if ui.button("Damage").clicked() {
+                            new_health.damage(*amount);
+                        }
+                        if ui.button("Heal").clicked() {
+                            new_health.heal(*amount, data.hit_points);
+                        }
+                        if ui.button("Temp HP").clicked() {
+                            new_health.add_temp(*amount);
+                        }
+                    });
+                    if roles.is_game_master() && owner.0.is_none() {
+                        ui.checkbox(&mut new_health.hidden, "Hide from players");
+                    }
+                    if new_health != *health {
+                        health::set_health(*id, new_health, &mut ev_client);
+                    }
+                }
+            });
+        });
+}
+
+fn initiative_tracker(
+    mut contexts: EguiContexts,
+    roles: Res<roles::Roles>,
+    initiative: Res<initiative::Initiative>,
+    tokens: Query<(&tokens::TokenId, &tokens::StrippedTokenData)>,
+    mut selected: Local<std::collections::HashSet<tokens::TokenId>>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+    mut events: EventWriter<InsertLog>,
+) {
+    let name_of = |id: tokens::TokenId| tokens.iter()
+        .find(|(token_id, _)| **token_id == id)
+        .map(|(_, data)| data.name.clone());
+    egui::Window::new("Initiative")
+        .default_open(false)
+        .anchor(egui::Align2::RIGHT_TOP, egui::vec2(-5., 5.))
+        .show(contexts.ctx_mut(), |ui| {
+            let round = if initiative.round == 0 { "Not started".to_string() } else { format!("Round {}", initiative.round) };
+            ui.label(round);
+            let active = initiative.active();
+            let mut new_initiative = initiative.clone();
+            let mut changed = false;
+            for entry in initiative.entries.iter() {
+                //Tokens deleted since they rolled
+                let Some(name) = name_of(entry.id) else {
+                    continue;
+                };
+                ui.horizontal(|ui| {
+                    let text = egui::RichText::new(format!("{} {}", entry.roll, name));
+                    ui.label(if active == Some(entry.id) { text.strong().color(egui::Color32::YELLOW) } else { text });
+                    if roles.is_game_master() && ui.small_button("x").clicked() {
+                        new_initiative.remove(entry.id);
+                        changed = true;
+                    }
+                });
+            }
+            if !roles.is_game_master() {
+                return;
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Previous").clicked() {
+                    new_initiative.previous_turn();
+                    changed = true;
+                }
+                let next_label = if initiative.round == 0 { "Start" } else { "Next" };
+                if ui.button(next_label).clicked() {
+                    new_initiative.next_turn();
+                    changed = true;
+                }
+                if ui.button("End Combat").clicked() {
+                    new_initiative.clear();
+                    changed = true;
+                }
+            });
+            ui.collapsing("Roll", |ui| {
+                egui::ScrollArea::vertical().max_height(200.).show(ui, |ui| {
+                    for (id, data) in tokens.iter() {
+                        let mut checked = selected.contains(id);
+                        if ui.checkbox(&mut checked, data.name.clone()).changed() {
+                            if checked {
+                                selected.insert(*id);
+                            } else {
+                                selected.remove(id);
+                            }
+                        }
+                    }
+                });
+                if ui.add_enabled(!selected.is_empty(), egui::Button::new("Roll Initiative")).clicked() {
+                    for (id, data) in tokens.iter().filter(|(id, _)| selected.contains(*id)) {
+                        match initiative::roll_initiative(*id, data.stats.dexterity) {
+                            Some(entry) => new_initiative.add(entry),
+                            None => events.send(InsertLog::new(format!("Couldn't roll initiative for {}", data.name))),
+                        }
+                    }
+                    selected.clear();
+                    changed = true;
+                }
+            });
+            if changed {
+                initiative::send_initiative(new_initiative, &mut ev_client);
+            }
+        });
+}
+
+//What's about to be added in the conditions window
+#[derive(Default)]
+struct ConditionPicker {
+    selected: usize,
+    custom: String,
+    //0 lasts until it's taken off
+    rounds: u32,
+}
+
+fn token_conditions(
+    mut contexts: EguiContexts,
+    roles: Res<roles::Roles>,
+    tokens: Query<(&tokens::TokenId, &tokens::TokenOwner, &tokens::StrippedTokenData, &conditions::TokenConditions)>,
+    mut picker: Local<ConditionPicker>,
+    mut ev_client: EventWriter<networking::ClientCommandEvent>,
+) {
+    egui::Window::new("Conditions")
+        .default_open(false)
+        .anchor(egui::Align2::RIGHT_BOTTOM, egui::vec2(-5., -45.))
+        .show(contexts.ctx_mut(), |ui| {
+            egui::Grid::new("condition_picker_grid").num_columns(2).show(ui, |ui| {
+                ui.label("Condition");
+                //One past the standard conditions is a custom marker
+                let selected_text = conditions::CONDITIONS.get(picker.selected)
+                    .map(|condition| condition.name().to_string())
+                    .unwrap_or("Custom".to_string());
+                egui::ComboBox::from_id_source("condition_picker")
+                    .selected_text(selected_text)
+                    .show_ui(ui, |ui| {
+                        for (index, condition) in conditions::CONDITIONS.iter().enumerate() {
+                            ui.selectable_value(&mut picker.selected, index, condition.name());
+                        }
+                        ui.selectable_value(&mut picker.selected, conditions::CONDITIONS.len(), "Custom");
+                    });
+                ui.end_row();
+                if picker.selected >= conditions::CONDITIONS.len() {
+                    ui.label("Name");
+                    ui.text_edit_singleline(&mut picker.custom);
+                    ui.end_row();
+                }
+                ui.label("Rounds");
+                ui.add(egui::DragValue::new(&mut picker.rounds).clamp_range(0..=100));
+                ui.end_row();
+            });
+            let condition = match conditions::CONDITIONS.get(picker.selected) {
+                Some(condition) => Some(condition.clone()),
+                None if !picker.custom.trim().is_empty() => Some(conditions::Condition::Custom(picker.custom.trim().to_string())),
+                None => None,
+            };
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                //Only the tokens we're allowed to change
+                for (id, _, data, token_conditions) in tokens.iter().filter(|(_, owner, ..)| roles.can_local(Some(owner))) {
+                    ui.separator();
+                    let mut new_conditions = token_conditions.clone();
+                    ui.horizontal(|ui| {
+                        ui.label(data.name.clone());
+                        if ui.add_enabled(condition.is_some(), egui::Button::new("Add")).clicked() {
+                            if let Some(condition) = condition.clone() {
+                                new_conditions.0.push(conditions::StatusMarker{
+                                    condition,
+                                    rounds: (picker.rounds > 0).then_some(picker.rounds),
+                                });
+                            }
+                        }
+                    });
+                    let mut removed = None;
+                    for (index, marker) in token_conditions.0.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            let [r, g, b] = marker.condition.color();
+                            ui.colored_label(egui::Color32::from_rgb((r * 255.) as u8, (g * 255.) as u8, (b * 255.) as u8), "■");
+                            match marker.rounds {
+                                Some(rounds) => ui.label(format!("{} ({} rounds)", marker.condition.name(), rounds)),
+                                None => ui.label(marker.condition.name()),
+                            };
+                            if ui.small_button("x").clicked() {
+                                removed = Some(index);
+                            }
+                        });
+                    }
+                    if let Some(index) = removed {
+                        new_conditions.0.remove(index);
+                    }
+                    if new_conditions != *token_conditions {
+                        conditions::set_conditions(*id, new_conditions, &mut ev_client);
+                    }
+                }
+            });
+        });
+}
+
+fn grid_settings(
+    mut contexts: EguiContexts,
+    mut settings: ResMut<grid::GridSettings>,
+) {
+    egui::Window::new("Grid")
+        .default_open(false)
+        .resizable(false)
+        .anchor(egui::Align2::LEFT_TOP, egui::vec2(5., 5.))
+        .show(contexts.ctx_mut(), |ui| {
+            ui.checkbox(&mut settings.visible, "Show Grid");
+            egui::Grid::new("grid_settings_grid").num_columns(2).show(ui, |ui| {
+                ui.label("Color");
+                ui.color_edit_button_rgb(&mut settings.color);
+                ui.end_row();
+                ui.label("Opacity");
+                ui.add(egui::Slider::new(&mut settings.opacity, 0.0..=1.0));
+                ui.end_row();
+                ui.label("Line Width");
+                ui.add(egui::Slider::new(&mut settings.line_width, 0.5..=5.0));
+                ui.end_row();
+            });
+            ui.label("Tokens snap to the grid, hold Alt to place them freely");
+        });
+}
+
+//Biggest the map preview gets in the calibration window
+const CALIBRATION_PREVIEW_SIZE: f32 = 600.;
+
+fn map_calibration(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut calibration: ResMut<input::MapCalibration>,
+    mut ev_calibrated: EventWriter<input::CalibratedMap>,
+) {
+    let texture = contexts.add_image(calibration.image.clone_weak());
+    let image_size = egui::vec2(calibration.width as f32, calibration.height as f32);
+    let scale = (CALIBRATION_PREVIEW_SIZE / image_size.x)
+        .min(CALIBRATION_PREVIEW_SIZE / image_size.y)
+        .min(1.);
+    let mut done = false;
+    let mut import = false;
+    egui::Window::new("Calibrate Map Grid")
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(contexts.ctx_mut(), |ui| {
+            ui.label("Drag over a single grid cell, then adjust if needed.");
+            let response = ui.add(
+                egui::Image::new(egui::load::SizedTexture::new(texture, image_size * scale))
+                    .sense(egui::Sense::drag())
+            );
+            let rect = response.rect;
+            let to_image = |pos: egui::Pos2| Vec2::new((pos.x - rect.min.x) / scale, (pos.y - rect.min.y) / scale);
+            if response.drag_started() {
+                calibration.drag_start = response.interact_pointer_pos().map(to_image);
+            }
+            if let (Some(start), Some(pos)) = (calibration.drag_start, response.interact_pointer_pos()) {
+                let end = to_image(pos);
+                calibration.set_cell(start, end);
+                if response.drag_released() {
+                    calibration.drag_start = None;
+                }
+            }
+
+            //Draw the grid as it currently stands so the user can see if it lines up
+            let painter = ui.painter_at(rect);
+            let stroke = egui::Stroke::new(1., egui::Color32::from_rgba_unmultiplied(255, 0, 0, 160));
+            let step = calibration.pixels_per as f32 * scale;
+            if step >= 2. {
+                let mut x = rect.min.x + calibration.offset_x as f32 * scale;
+                while x <= rect.max.x {
+                    painter.vline(x, rect.y_range(), stroke);
+                    x += step;
+                }
+                let mut y = rect.min.y + calibration.offset_y as f32 * scale;
+                while y <= rect.max.y {
+                    painter.hline(rect.x_range(), y, stroke);
+                    y += step;
+                }
+            }
+
+            egui::Grid::new("calibration_grid").num_columns(2).show(ui, |ui| {
+                ui.label("Pixels per cell");
+                ui.add(egui::DragValue::new(&mut calibration.pixels_per).clamp_range(1.0..=4096.0).speed(0.1));
+                ui.end_row();
+                ui.label("Offset");
+                ui.horizontal(|ui| {
+                    let max = calibration.pixels_per;
+                    ui.add(egui::DragValue::new(&mut calibration.offset_x).clamp_range(0.0..=max).speed(0.1));
+                    ui.add(egui::DragValue::new(&mut calibration.offset_y).clamp_range(0.0..=max).speed(0.1));
+                });
+                ui.end_row();
+            });
+            let grid = calibration.grid();
+            ui.label(format!("{} x {} cells", grid.width, grid.height));
+            ui.horizontal(|ui| {
+                if ui.button("Import").clicked() {
+                    import = true;
+                    done = true;
+                }
+                if ui.button("Cancel").clicked() {
+                    done = true;
+                }
+            });
+        });
+    if import {
+        ev_calibrated.send(input::CalibratedMap {
+            name: calibration.name.clone(),
+            data: maps::MapData::from_image(&calibration.file, calibration.grid()),
+        });
+    }
+    if done {
+        contexts.remove_image(&calibration.image);
+        commands.remove_resource::<input::MapCalibration>();
+    }
+}
+
+fn connection_status(
+    status: Res<networking::ConnectionStatus>,
+    mut contexts: EguiContexts,
+) {
+    let (text, color) = match status.state {
+        networking::ConnectionState::NotJoined => return,
+        networking::ConnectionState::Connecting => ("Connecting...".to_string(), egui::Color32::YELLOW),
+        networking::ConnectionState::Connected => (format!("Connected - {} peers", status.peers.len()), egui::Color32::GREEN),
+        networking::ConnectionState::Reconnecting => (
+            format!("Disconnected - retrying in {:.0}s", status.retry.remaining_secs().ceil()),
+            egui::Color32::RED,
+        ),
+    };
+    egui::Area::new("Connection Status")
+        .anchor(egui::Align2::CENTER_TOP, egui::vec2(0., 5.))
+        .show(contexts.ctx_mut(), |ui| {
+            ui.colored_label(color, text);
+        });
+}
+
 #[derive(Resource)]
 struct Log {
     messages: VecDeque<Message>,
@@ -325,6 +942,7 @@ struct TextMessages {
 struct TextMessage {
     text: String,
     from: bevy_matchbox::prelude::PeerId,
+    name: String,
     roll: bool,
 }
 
@@ -332,6 +950,7 @@ struct TextMessage {
 pub struct RecieveMessage {
     pub text: String,
     pub from: bevy_matchbox::prelude::PeerId,
+    pub name: String,
     pub roll: bool,
 }
 
@@ -344,6 +963,7 @@ fn update_messages(
             TextMessage{
                 text: ev.text.clone(),
                 from: ev.from.clone(),
+                name: ev.name.clone(),
                 roll: ev.roll,
             }
         )
@@ -360,6 +980,7 @@ fn text_messages(
     mut contexts: EguiContexts,
     mut ev_client: EventWriter<networking::ClientCommandEvent>,
     local_peer_id: Option<Res<networking::LocalPeerId>>,
+    join: Res<join::JoinScreen>,
 ) {
     let Some(local_peer_id) = local_peer_id else {
         return
@@ -372,7 +993,7 @@ fn text_messages(
                     ui.text_edit_singleline(&mut log.input);
                     let btn = ui.button("Send");
                     if btn.clicked() {
-                        input::send_message(log.input.clone(), &mut ev_client, local_peer_id);
+                        input::send_message(log.input.clone(), join.settings.name.clone(), &mut ev_client, local_peer_id);
                     }
                 });
                 
@@ -381,7 +1002,8 @@ fn text_messages(
                         if message.roll {
                             ui.label("ROLL");
                         }
-                        ui.label(format!("{} : {}", &message.from, &message.text));
+                        ui.label(format!("{} : {}", &message.name, &message.text))
+                            .on_hover_text(message.from.to_string());
                     });
                 }
             });
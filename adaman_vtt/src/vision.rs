@@ -0,0 +1,469 @@
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+use bevy_mod_picking::prelude::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::bank;
+use crate::encounters;
+use crate::files;
+use crate::fog;
+use crate::maps;
+use crate::roles;
+use crate::tokens;
+
+pub struct VisionPlugin;
+
+impl Plugin for VisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(VisionSettings{
+                show_walls: true,
+            })
+            //Closed from the start, so players don't see everything while they're joining
+            .insert_resource(LineOfSight{
+                active: true,
+                ..default()
+            })
+            .insert_resource(ExploredSaveTimer(Timer::from_seconds(EXPLORED_SAVE_SECS, TimerMode::Repeating)))
+            .add_systems(Update, spawn_vision_masks)
+            .add_systems(Update, load_explored)
+            .add_systems(Update, update_line_of_sight.after(spawn_vision_masks).after(load_explored))
+            .add_systems(Update, update_vision_masks.after(update_line_of_sight))
+            .add_systems(Update, hide_unseen_tokens.after(update_line_of_sight))
+            .add_systems(Update, draw_walls)
+            .add_systems(Update, save_explored.after(update_vision_masks))
+        ;
+    }
+}
+
+#[derive(Resource)]
+pub struct VisionSettings {
+    //Only ever drawn for the GM
+    pub show_walls: bool,
+}
+
+//What the local player's tokens can see, as polygons on the ground plane
+#[derive(Resource, Default)]
+pub struct LineOfSight {
+    //False for the GM, who sees everything
+    pub active: bool,
+    //Where each of the player's tokens is and how far it can see
+    pub viewers: Vec<(Vec2, f32)>,
+    pub polygons: Vec<Vec<Vec2>>,
+}
+
+impl LineOfSight {
+    pub fn can_see(&self, point: Vec2) -> bool {
+        !self.active || self.viewers.iter()
+            .zip(self.polygons.iter())
+            .any(|((viewer, range), polygon)| viewer.distance(point) <= *range && contains(polygon, point))
+    }
+}
+
+//The parts of a map this client's tokens have seen before, kept per encounter
+#[derive(Component, Clone, Default)]
+pub struct Explored {
+    pub width: u32,
+    pub height: u32,
+    pub texels: Vec<bool>,
+    //Changed since it was last saved
+    pub dirty: bool,
+}
+
+impl Explored {
+    //Whether the texel or any next to it has been seen, for things sitting right on a wall
+    pub fn near(&self, x: u32, y: u32) -> bool {
+        (y.saturating_sub(1)..=(y + 1).min(self.height.saturating_sub(1)))
+            .flat_map(|y| (x.saturating_sub(1)..=(x + 1).min(self.width.saturating_sub(1))).map(move |x| (x, y)))
+            .any(|(x, y)| self.texels.get((y * self.width + x) as usize).copied().unwrap_or(false))
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ExploredSave {
+    maps: Vec<(maps::MapId, u32, u32, Vec<u32>)>,
+}
+
+//The bank id explored areas are saved under for an encounter
+fn explored_id(encounter: &bank::DataId) -> bank::DataId {
+    bank::DataId(uuid::Uuid::from_u128(encounter.0.as_u128() ^ files::EXPLORED_ID.0.as_u128()))
+}
+
+#[derive(Resource)]
+struct ExploredSaveTimer(Timer);
+
+const EXPLORED_SAVE_SECS: f32 = 5.;
+
+//Darkness drawn over a map wherever the local player can't see or the GM has fogged
+#[derive(Component)]
+pub struct VisionMask {
+    pub image: Handle<Image>,
+    pub width: u32,
+    pub height: u32,
+}
+
+//Just above the map, below the tokens
+const MASK_HEIGHT: f32 = 0.02;
+const WALL_HEIGHT: f32 = 0.03;
+
+fn spawn_vision_masks(
+    mut commands: Commands,
+    maps: Query<(Entity, &maps::MapDimensions), Added<maps::MapDimensions>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut images: ResMut<Assets<Image>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for (entity, dimensions) in maps.iter() {
+        let (width, height) = dimensions.texels();
+        let image = images.add(Image::new_fill(
+            Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            &[0, 0, 0, 255],
+            TextureFormat::Rgba8UnormSrgb,
+        ));
+        let quad = shape::Quad {
+            size: dimensions.size,
+            flip: false,
+        };
+        let mask = commands.spawn((
+            PbrBundle {
+                mesh: meshes.add(quad.into()),
+                material: materials.add(StandardMaterial {
+                    base_color_texture: Some(image.clone()),
+                    alpha_mode: AlphaMode::Blend,
+                    unlit: true,
+                    ..default()
+                }),
+                transform: Transform::from_xyz(0., 0., MASK_HEIGHT),
+                visibility: Visibility::Hidden,
+                ..default()
+            },
+            Pickable::IGNORE,
+            VisionMask {
+                image,
+                width,
+                height,
+            },
+        )).id();
+        commands.entity(entity).push_children(&[mask]);
+    }
+}
+
+fn update_line_of_sight(
+    mut los: ResMut<LineOfSight>,
+    roles: Res<roles::Roles>,
+    maps: Query<(&maps::MapDimensions, &GlobalTransform, &maps::MapDoors)>,
+    changed_maps: Query<(), Or<(Changed<maps::MapDimensions>, Changed<maps::MapDoors>)>>,
+    mut removed_maps: RemovedComponents<maps::MapDimensions>,
+    tokens: Query<(&Transform, &tokens::TokenOwner, Option<&tokens::StrippedTokenData>), With<tokens::TokenFlag>>,
+) {
+    //Players see nothing but their own tokens until a map gives them something to look around
+    let active = !roles.is_game_master();
+    let viewers: Vec<(Vec2, f32)> = tokens.iter()
+        .filter(|(_, owner, _)| roles.is_local_owner(owner))
+        .map(|(transform, _, data)| (
+            Vec2::new(transform.translation.x, transform.translation.z),
+            data.map(|x| x.get_vision_range()).unwrap_or(tokens::DEFAULT_VISION_RANGE),
+        ))
+        .collect();
+    let maps_changed = !changed_maps.is_empty() || removed_maps.read().count() > 0;
+    //Only redo the expensive part when something that matters moved
+    if !maps_changed && los.active == active && los.viewers == viewers {
+        return;
+    }
+
+    let mut segments = Vec::new();
+    for (dimensions, transform, doors) in maps.iter() {
+        segments.append(&mut dimensions.wall_segments(transform));
+        segments.append(&mut dimensions.door_segments(transform, doors));
+        let bounds = dimensions.bounds(transform);
+        for i in 0..bounds.len() {
+            segments.push((bounds[i], bounds[(i + 1) % bounds.len()]));
+        }
+    }
+    los.polygons = if active {
+        viewers.iter()
+            .map(|(viewer, _)| visibility_polygon(*viewer, &segments))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    los.active = active;
+    los.viewers = viewers;
+}
+
+//How dark the fog looks to the GM, who still needs to see what's under it
+const GM_FOG_ALPHA: u8 = 140;
+//Places the player has been but can't see right now
+const EXPLORED_ALPHA: u8 = 170;
+
+//Dragging a token changes the line of sight every frame, the mask doesn't need to keep up that closely
+const MASK_UPDATE_SECS: f32 = 0.1;
+
+#[allow(clippy::too_many_arguments)]
+pub fn update_vision_masks(
+    los: Res<LineOfSight>,
+    roles: Res<roles::Roles>,
+    time: Res<Time>,
+    mut masks: Query<(&VisionMask, &Parent, &mut Visibility)>,
+    mut maps: Query<(&maps::MapDimensions, &GlobalTransform, &fog::MapFog, &mut Explored)>,
+    changed_fog: Query<(), Changed<fog::MapFog>>,
+    mut images: ResMut<Assets<Image>>,
+    mut pending: Local<bool>,
+    mut last_update: Local<f32>,
+) {
+    if los.is_changed() || roles.is_changed() || !changed_fog.is_empty() {
+        *pending = true;
+    }
+    let now = time.elapsed_seconds();
+    if !*pending || now - *last_update < MASK_UPDATE_SECS {
+        return;
+    }
+    *pending = false;
+    *last_update = now;
+    for (mask, parent, mut visibility) in masks.iter_mut() {
+        let Ok((dimensions, transform, fog, mut explored)) = maps.get_mut(parent.get()) else {
+            continue;
+        };
+        if !los.active && !fog.enabled {
+            *visibility = Visibility::Hidden;
+            continue;
+        }
+        let Some(image) = images.get_mut(&mask.image) else {
+            continue;
+        };
+        if explored.width != mask.width || explored.height != mask.height {
+            explored.width = mask.width;
+            explored.height = mask.height;
+            explored.texels = vec![false; (mask.width * mask.height) as usize];
+        }
+        let mut visible = vec![!los.active; (mask.width * mask.height) as usize];
+        if los.active {
+            for ((viewer, range), polygon) in los.viewers.iter().zip(los.polygons.iter()) {
+                let polygon: Vec<Vec2> = polygon.iter()
+                    .map(|point| dimensions.world_to_texel_space(transform, *point))
+                    .collect();
+                let viewer = dimensions.world_to_texel_space(transform, *viewer);
+                let range = range / maps::CELL_SIZE * maps::TEXELS_PER_CELL;
+                fill_polygon(&mut visible, mask.width, mask.height, &polygon, viewer, range);
+            }
+        }
+        for (i, seen) in visible.iter().enumerate() {
+            let x = i as u32 % mask.width;
+            let y = i as u32 / mask.width;
+            let fogged = fog.is_hidden(x, y);
+            let alpha = if roles.is_game_master() {
+                if fogged { GM_FOG_ALPHA } else { 0 }
+            } else if fogged {
+                255
+            } else if *seen {
+                if !explored.texels[i] {
+                    explored.texels[i] = true;
+                    explored.dirty = true;
+                }
+                0
+            } else if explored.texels[i] {
+                EXPLORED_ALPHA
+            } else {
+                255
+            };
+            image.data[i * 4 + 3] = alpha;
+        }
+        *visibility = Visibility::Visible;
+    }
+}
+
+//Scanline fill of a polygon in texel space, marking every texel whose middle is inside it and within range of the viewer
+fn fill_polygon(visible: &mut [bool], width: u32, height: u32, polygon: &[Vec2], viewer: Vec2, range: f32) {
+    if polygon.len() < 3 {
+        return;
+    }
+    let top = polygon.iter().map(|point| point.y).fold(f32::INFINITY, f32::min).max(viewer.y - range);
+    let bottom = polygon.iter().map(|point| point.y).fold(f32::NEG_INFINITY, f32::max).min(viewer.y + range);
+    let first_row = (top - 0.5).ceil().max(0.) as u32;
+    let last_row = ((bottom - 0.5).floor() + 1.).clamp(0., height as f32) as u32;
+    let mut crossings = Vec::<f32>::new();
+    for y in first_row..last_row {
+        let row = y as f32 + 0.5;
+        //Same edge rule as contains, so the two agree on what's inside
+        crossings.clear();
+        let mut j = polygon.len() - 1;
+        for i in 0..polygon.len() {
+            let a = polygon[i];
+            let b = polygon[j];
+            if (a.y > row) != (b.y > row) {
+                crossings.push((b.x - a.x) * (row - a.y) / (b.y - a.y) + a.x);
+            }
+            j = i;
+        }
+        crossings.sort_by(|a, b| a.total_cmp(b));
+        //Only the part of the row inside the viewer's range
+        let reach = (range * range - (row - viewer.y).powi(2)).max(0.).sqrt();
+        for span in crossings.chunks_exact(2) {
+            let start = span[0].max(viewer.x - reach);
+            let end = span[1].min(viewer.x + reach);
+            let first = (start - 0.5).ceil().max(0.) as u32;
+            let last = ((end - 0.5).ceil()).clamp(0., width as f32) as u32;
+            for x in first..last {
+                visible[(y * width + x) as usize] = true;
+            }
+        }
+    }
+}
+
+//Pick up where this client left off the last time it was in this encounter
+fn load_explored(
+    mut maps: Query<(&maps::MapId, &maps::MapDimensions, &mut Explored), Added<maps::MapDimensions>>,
+    current_encounter: Res<encounters::CurrentEncounterID>,
+    bank: Res<bank::Bank>,
+) {
+    if maps.is_empty() {
+        return;
+    }
+    let save = bank.request_data(&explored_id(&current_encounter.0))
+        .and_then(|data| serde_json::from_slice::<ExploredSave>(data.as_slice()).ok())
+        .unwrap_or_default();
+    for (map_id, dimensions, mut explored) in maps.iter_mut() {
+        let (width, height) = dimensions.texels();
+        let texels = save.maps.iter()
+            .find(|(id, w, h, _)| id == map_id && *w == width && *h == height)
+            .and_then(|(_, _, _, runs)| fog::decode_runs(runs, (width * height) as usize))
+            .unwrap_or(vec![false; (width * height) as usize]);
+        *explored = Explored {
+            width,
+            height,
+            texels,
+            dirty: false,
+        };
+    }
+}
+
+fn save_explored(
+    time: Res<Time>,
+    mut timer: ResMut<ExploredSaveTimer>,
+    mut maps: Query<(&maps::MapId, &mut Explored)>,
+    current_encounter: Res<encounters::CurrentEncounterID>,
+    mut bank: ResMut<bank::Bank>,
+) {
+    //Exploring changes a lot, so only write it out every so often
+    if !timer.0.tick(time.delta()).just_finished() {
+        return;
+    }
+    if !maps.iter().any(|(_, explored)| explored.dirty) {
+        return;
+    }
+    let id = explored_id(&current_encounter.0);
+    //Keep maps from this encounter that aren't on the board right now
+    let mut save = bank.request_data(&id)
+        .and_then(|data| serde_json::from_slice::<ExploredSave>(data.as_slice()).ok())
+        .unwrap_or_default();
+    for (map_id, mut explored) in maps.iter_mut() {
+        explored.dirty = false;
+        save.maps.retain(|(id, _, _, _)| id != map_id);
+        save.maps.push((*map_id, explored.width, explored.height, fog::encode_runs(&explored.texels)));
+    }
+    let Ok(data) = serde_json::to_vec(&save) else {
+        return;
+    };
+    bank.store_at_id(&id, data.into());
+}
+
+fn hide_unseen_tokens(
+    los: Res<LineOfSight>,
+    roles: Res<roles::Roles>,
+    mut tokens: Query<(&Transform, &tokens::TokenOwner, &mut Visibility), With<tokens::TokenFlag>>,
+    maps: Query<(&maps::MapDimensions, &GlobalTransform, &fog::MapFog)>,
+) {
+    for (transform, owner, mut visibility) in tokens.iter_mut() {
+        let position = Vec2::new(transform.translation.x, transform.translation.z);
+        //Players can't see into fog, even with their own eyes
+        let fogged = !roles.is_game_master() && maps.iter().any(|(dimensions, map_transform, fog)| {
+            dimensions.world_to_texel(map_transform, position)
+                .is_some_and(|(x, y)| fog.is_hidden(x, y))
+        });
+        let seen = roles.is_local_owner(owner)
+            || (!fogged && los.can_see(position));
+        let wanted = if seen { Visibility::Inherited } else { Visibility::Hidden };
+        if *visibility != wanted {
+            *visibility = wanted;
+        }
+    }
+}
+
+fn draw_walls(
+    settings: Res<VisionSettings>,
+    roles: Res<roles::Roles>,
+    maps: Query<(&maps::MapDimensions, &GlobalTransform)>,
+    mut gizmos: Gizmos,
+) {
+    if !roles.is_game_master() || !settings.show_walls {
+        return;
+    }
+    for (dimensions, transform) in maps.iter() {
+        for (start, end) in dimensions.wall_segments(transform) {
+            gizmos.line(
+                Vec3::new(start.x, WALL_HEIGHT, start.y),
+                Vec3::new(end.x, WALL_HEIGHT, end.y),
+                Color::ORANGE_RED,
+            );
+        }
+    }
+}
+
+//Cast rays at every wall corner, and just either side of it, to find the outline of what can be seen
+pub fn visibility_polygon(origin: Vec2, segments: &[(Vec2, Vec2)]) -> Vec<Vec2> {
+    const OFFSET: f32 = 0.0001;
+    let mut angles = Vec::new();
+    for (start, end) in segments.iter() {
+        for point in [start, end] {
+            let angle = (*point - origin).y.atan2((*point - origin).x);
+            angles.extend([angle - OFFSET, angle, angle + OFFSET]);
+        }
+    }
+    angles.sort_by(|a, b| a.total_cmp(b));
+    angles.dedup();
+
+    angles.iter()
+        .filter_map(|angle| {
+            let dir = Vec2::new(angle.cos(), angle.sin());
+            cast_ray(origin, dir, segments).map(|distance| origin + dir * distance)
+        })
+        .collect()
+}
+
+//Distance to the closest segment along the ray, if it hits any
+fn cast_ray(origin: Vec2, dir: Vec2, segments: &[(Vec2, Vec2)]) -> Option<f32> {
+    let normal = Vec2::new(-dir.y, dir.x);
+    segments.iter()
+        .filter_map(|(start, end)| {
+            let to_origin = origin - *start;
+            let along = *end - *start;
+            let dot = along.dot(normal);
+            if dot.abs() < 1e-6 {
+                return None;
+            }
+            let distance = along.perp_dot(to_origin) / dot;
+            let fraction = to_origin.dot(normal) / dot;
+            (distance >= 0. && (0. ..=1.).contains(&fraction)).then_some(distance)
+        })
+        .min_by(|a, b| a.total_cmp(b))
+}
+
+pub fn contains(polygon: &[Vec2], point: Vec2) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len().wrapping_sub(1);
+    for i in 0..polygon.len() {
+        let a = polygon[i];
+        let b = polygon[j];
+        if (a.y > point.y) != (b.y > point.y)
+            && point.x < (b.x - a.x) * (point.y - a.y) / (b.y - a.y) + a.x {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}